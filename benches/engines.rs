@@ -0,0 +1,133 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvStoreConfig, KvsEngine, SledKvsEngine, SyncMode};
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+use tempfile::TempDir;
+
+// Keys populated before a workload runs, the workload reads and overwrites these
+const KEYS: usize = 1000;
+// Operations in one iteration of a workload
+const OPS: usize = 1000;
+
+// Sizes of the keys and values along with the share of operations that are reads,
+// add a scenario here to compare the engines under another mix
+struct Workload {
+    name: &'static str,
+    key_size: usize,
+    value_size: usize,
+    read_percent: u32,
+}
+
+const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "write_heavy",
+        key_size: 16,
+        value_size: 100,
+        read_percent: 10,
+    },
+    Workload {
+        name: "read_heavy",
+        key_size: 16,
+        value_size: 100,
+        read_percent: 90,
+    },
+    Workload {
+        name: "write_heavy_large_values",
+        key_size: 16,
+        value_size: 4096,
+        read_percent: 10,
+    },
+    Workload {
+        name: "read_heavy_large_values",
+        key_size: 16,
+        value_size: 4096,
+        read_percent: 90,
+    },
+];
+
+enum Op {
+    Get(String),
+    Set(String, String),
+}
+
+fn random_string(rng: &mut StdRng, len: usize) -> String {
+    rng.sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// The keys to populate, and the operations of an iteration drawn from them, the
+// same for every engine as the generator is seeded
+fn generate(workload: &Workload) -> (Vec<(String, String)>, Vec<Op>) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let entries: Vec<_> = (0..KEYS)
+        .map(|_| {
+            (
+                random_string(&mut rng, workload.key_size),
+                random_string(&mut rng, workload.value_size),
+            )
+        })
+        .collect();
+    let ops = (0..OPS)
+        .map(|_| {
+            let key = entries[rng.gen_range(0..KEYS)].0.clone();
+            if rng.gen_range(0..100) < workload.read_percent {
+                Op::Get(key)
+            } else {
+                Op::Set(key, random_string(&mut rng, workload.value_size))
+            }
+        })
+        .collect();
+    (entries, ops)
+}
+
+fn populate(engine: &mut impl KvsEngine, entries: &[(String, String)]) {
+    for (key, value) in entries {
+        engine.set(key.clone(), value.clone()).unwrap();
+    }
+}
+
+fn run(engine: &mut impl KvsEngine, ops: &[Op]) {
+    for op in ops {
+        match op {
+            Op::Get(key) => {
+                engine.get(key.clone()).unwrap();
+            }
+            Op::Set(key, value) => engine.set(key.clone(), value.clone()).unwrap(),
+        }
+    }
+}
+
+// Each workload against both engines, opened over a populated directory of their own
+fn workloads(c: &mut Criterion) {
+    for workload in WORKLOADS {
+        let (entries, ops) = generate(workload);
+        let mut group = c.benchmark_group(workload.name);
+        group.throughput(Throughput::Elements(OPS as u64));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // synced like the sled engine, which flushes every write, and only compacted
+        // once there is something to reclaim, as the large values alone outgrow the
+        // default threshold
+        let config = KvStoreConfig::new()
+            .sync(SyncMode::Always)
+            .dead_ratio(Some(0.5));
+        let mut store = KvStore::open_with(temp_dir.path(), config).unwrap();
+        populate(&mut store, &entries);
+        group.bench_with_input(BenchmarkId::from_parameter("kvs"), &ops, |b, ops| {
+            b.iter(|| run(&mut store, ops))
+        });
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut sled = SledKvsEngine::open(temp_dir.path()).unwrap();
+        populate(&mut sled, &entries);
+        group.bench_with_input(BenchmarkId::from_parameter("sled"), &ops, |b, ops| {
+            b.iter(|| run(&mut sled, ops))
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, workloads);
+criterion_main!(benches);
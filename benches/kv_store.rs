@@ -0,0 +1,94 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreConfig, LogFormat};
+use std::thread;
+use tempfile::TempDir;
+
+// Many sequential sets through the same open log handle
+fn sequential_set(c: &mut Criterion) {
+    c.bench_function("sequential_set", |b| {
+        b.iter_batched(
+            || TempDir::new().expect("unable to create temporary working directory"),
+            |temp_dir| {
+                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                for key_id in 0..1000 {
+                    store
+                        .set(format!("key{}", key_id), format!("value{}", key_id))
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+// Repeated reads of the same key through the same open read handle
+fn repeated_get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    c.bench_function("repeated_get", |b| b.iter(|| store.get("key").unwrap()));
+}
+
+// Rebuilding the index on open from a log in each format
+fn startup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("startup");
+    for (name, format) in [("json", LogFormat::Json), ("bincode", LogFormat::Bincode)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig::new().threshold(None).format(format);
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone()).unwrap();
+        for key_id in 0..10000 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .unwrap();
+        }
+        drop(store);
+
+        group.bench_function(name, |b| {
+            b.iter(|| KvStore::open_with(temp_dir.path(), config.clone()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Reads spread over a growing number of threads, each with its own handle
+fn concurrent_get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..1000 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .unwrap();
+    }
+
+    let mut group = c.benchmark_group("concurrent_get");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for _ in 0..threads {
+                            let mut store = store.clone();
+                            scope.spawn(move || {
+                                for key_id in 0..1000 {
+                                    store.get(format!("key{}", key_id)).unwrap();
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    sequential_set,
+    repeated_get,
+    startup,
+    concurrent_get
+);
+criterion_main!(benches);
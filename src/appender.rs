@@ -0,0 +1,217 @@
+use crate::format::Codec;
+use crate::storage::Handle;
+use crate::wal::Segments;
+use crate::{KvsError, Result, SyncMode};
+use crossbeam_skiplist::SkipMap;
+use std::{
+    io::{self, BufWriter, Write},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+// Frames handed to the writer thread that haven't reached their segment yet, by
+// segment and offset, so that reads can be served before they do
+pub(crate) type Pending = Arc<SkipMap<(u64, usize), (Codec, Vec<u8>)>>;
+
+enum Job {
+    // write out the pending frame at this segment and offset
+    Append(u64, usize),
+    // reply once every earlier frame is written out, synced as well if asked to
+    Drain(bool, Sender<io::Result<()>>),
+}
+
+// Appends frames to the active segment on a thread of its own, the caller only
+// waits for the frame to be queued. Frames are written out in the order they
+// were queued and in batches of whatever queued up in the meantime, the sync mode
+// applies to each batch. A failed write stops the thread writing, the failure
+// is reported by every later call.
+#[derive(Debug)]
+pub(crate) struct Appender {
+    jobs: Option<Sender<Job>>, // taken on drop to stop the thread
+    thread: Option<JoinHandle<()>>,
+    failed: Arc<Mutex<Option<io::Error>>>,
+    pending: Pending,
+}
+
+impl Appender {
+    pub(crate) fn spawn(segments: Segments, sync: SyncMode, pending: Pending) -> Result<Self> {
+        let (jobs, queue) = mpsc::channel();
+        let failed = Arc::new(Mutex::new(None));
+        let mut writer = Writer {
+            segments,
+            sync,
+            pending: pending.clone(),
+            failed: failed.clone(),
+            file: None,
+            batch: Vec::new(),
+            unsynced: 0,
+        };
+        let thread = thread::Builder::new()
+            .name("kvs-appender".to_owned())
+            .spawn(move || writer.run(queue))?;
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            failed,
+            pending,
+        })
+    }
+
+    // Queue a frame for the segment, to be written at the offset it is expected at
+    pub(crate) fn append(
+        &self,
+        segment: u64,
+        start: usize,
+        codec: Codec,
+        frame: Vec<u8>,
+    ) -> Result<()> {
+        self.check()?;
+        self.pending.insert((segment, start), (codec, frame));
+        self.send(Job::Append(segment, start))
+    }
+
+    // Wait for every queued frame to be written out, and synced with `sync`
+    pub(crate) fn drain(&self, sync: bool) -> Result<()> {
+        let (done, reply) = mpsc::channel();
+        self.send(Job::Drain(sync, done))?;
+        reply.recv().map_err(|_| stopped())??;
+        self.check()
+    }
+
+    fn send(&self, job: Job) -> Result<()> {
+        match &self.jobs {
+            Some(jobs) => jobs.send(job).map_err(|_| stopped()),
+            None => Err(stopped()),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match &*self.failed.lock().unwrap() {
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Appender {
+    // the thread writes out what is still queued before it stops
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stopped() -> KvsError {
+    io::Error::new(io::ErrorKind::BrokenPipe, "background writer stopped").into()
+}
+
+struct Writer {
+    segments: Segments,
+    sync: SyncMode,
+    pending: Pending,
+    failed: Arc<Mutex<Option<io::Error>>>,
+    file: Option<(u64, BufWriter<Handle>)>, // segment being written
+    batch: Vec<(u64, usize)>,               // frames written since the last flush
+    unsynced: u64,
+}
+
+impl Writer {
+    fn run(&mut self, queue: Receiver<Job>) {
+        while let Ok(job) = queue.recv() {
+            self.handle(job);
+            while let Ok(job) = queue.try_recv() {
+                self.handle(job);
+            }
+            let due = match self.sync {
+                SyncMode::Always => true,
+                SyncMode::Never => false,
+                SyncMode::EveryN(n) => self.unsynced >= n,
+            };
+            let result = self.finish(due);
+            self.record(result);
+        }
+        let result = self.finish(false);
+        self.record(result);
+    }
+
+    fn handle(&mut self, job: Job) {
+        match job {
+            Job::Append(segment, start) => {
+                let result = self.write(segment, start);
+                self.record(result);
+            }
+            Job::Drain(sync, done) => {
+                let result = self.finish(sync);
+                let _ = done.send(match &result {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                });
+                self.record(result);
+            }
+        }
+    }
+
+    fn write(&mut self, segment: u64, start: usize) -> io::Result<()> {
+        if self.failed.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        let pending = self.pending.clone();
+        let entry = match pending.get(&(segment, start)) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        if !matches!(self.file, Some((id, _)) if id == segment) {
+            // appends have moved on to the next segment
+            self.flush_batch()?;
+            let file = self
+                .segments
+                .storage
+                .open_append(&self.segments.path(segment))?;
+            self.file = Some((segment, BufWriter::new(file)));
+        }
+        if let Some((_, file)) = &mut self.file {
+            file.write_all(&entry.value().1)?;
+        }
+        self.batch.push((segment, start));
+        self.unsynced += 1;
+        Ok(())
+    }
+
+    // Write out the batch, syncing it with `sync`, then stop serving it from memory
+    fn finish(&mut self, sync: bool) -> io::Result<()> {
+        self.flush_batch()?;
+        if sync {
+            if let Some((_, file)) = &self.file {
+                file.get_ref().sync_data()?;
+            }
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> io::Result<()> {
+        if let Some((_, file)) = &mut self.file {
+            file.flush()?;
+        }
+        for frame in self.batch.drain(..) {
+            self.pending.remove(&frame);
+        }
+        Ok(())
+    }
+
+    // Keep the first failure for the appender to report, frames queued after it
+    // are not written and stay pending
+    fn record(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            let mut failed = self.failed.lock().unwrap();
+            if failed.is_none() {
+                *failed = Some(e);
+            }
+        }
+    }
+}
@@ -1 +1,62 @@
-fn main() {}
+use clap::{Args, Parser, Subcommand};
+use kvs::{KvsClient, Result};
+use std::net::SocketAddr;
+use std::process::exit;
+
+#[derive(Parser)]
+#[command(version, about, long_about=None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    Set {
+        k: String,
+        v: String,
+        #[command(flatten)]
+        server: Server,
+    },
+    Get {
+        k: String,
+        #[command(flatten)]
+        server: Server,
+    },
+    Rm {
+        k: String,
+        #[command(flatten)]
+        server: Server,
+    },
+}
+
+#[derive(Args)]
+struct Server {
+    /// Address of the server
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli.command) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Set { k, v, server } => {
+            KvsClient::connect(server.addr)?.set(k, v)?;
+        }
+        Commands::Get { k, server } => match KvsClient::connect(server.addr)?.get(k)? {
+            Some(v) => println!("{}", v),
+            None => println!("Key not found"),
+        },
+        Commands::Rm { k, server } => {
+            KvsClient::connect(server.addr)?.remove(k)?;
+        }
+    }
+    Ok(())
+}
@@ -1 +1,99 @@
-fn main() {}
+use clap::{Parser, ValueEnum};
+use kvs::{
+    resolve_engine, InMemoryKvsEngine, KvStore, KvsEngine, KvsError, KvsServer, Result,
+    SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+};
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::thread;
+
+#[derive(Parser)]
+#[command(version, about, long_about=None)]
+struct Cli {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    /// Storage engine, defaults to the one already in use or kvs
+    #[arg(long, value_enum)]
+    engine: Option<Engine>,
+    /// Number of connections served at once, further ones wait to be accepted
+    #[arg(long, default_value_t = 1024)]
+    max_connections: usize,
+    /// Number of connections that can wait to be accepted before new ones are refused
+    #[arg(long, default_value_t = 128)]
+    backlog: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+    /// Keeps everything in memory, nothing survives a restart. Any data already in
+    /// the directory is left alone.
+    Memory,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+            Engine::Memory => "memory",
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let dir = env::current_dir()?;
+    let engine = match cli.engine {
+        // nothing is kept on disk, whatever the directory holds
+        Some(Engine::Memory) => Engine::Memory,
+        requested => {
+            let engine = resolve_engine(&dir, requested.map(|e| e.name()))?;
+            Engine::from_str(&engine, false).map_err(|_| KvsError::WrongEngine(engine))?
+        }
+    };
+
+    eprintln!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    eprintln!("Storage engine: {}", engine.name());
+    eprintln!("Listening on {}", cli.addr);
+
+    match engine {
+        Engine::Kvs => serve(KvStore::open(&dir)?, &cli),
+        Engine::Sled => serve(SledKvsEngine::open(&dir)?, &cli),
+        Engine::Memory => serve(InMemoryKvsEngine::new(), &cli),
+    }
+}
+
+fn serve<E: KvsEngine + Clone + Send + 'static>(engine: E, cli: &Cli) -> Result<()> {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get() as u32);
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(threads)?)
+        .max_connections(cli.max_connections)
+        .backlog(cli.backlog);
+
+    // the first SIGINT or SIGTERM shuts down cleanly, a second one exits at once
+    let shutdown = server.shutdown_handle();
+    let mut signalled = false;
+    ctrlc::set_handler(move || {
+        if signalled {
+            eprintln!("Exiting without waiting for connections");
+            exit(130);
+        }
+        signalled = true;
+        eprintln!("Shutting down");
+        shutdown.shutdown();
+    })
+    .map_err(io::Error::other)?;
+
+    server.run(cli.addr)
+}
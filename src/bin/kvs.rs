@@ -1,50 +1,166 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvStore, KvsError, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{resolve_engine, KvStore, KvStoreConfig, KvsEngine, KvsError, Result, SledKvsEngine};
+use serde_json::json;
 use std::env;
-use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
 #[derive(Parser)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, after_help = ENV_HELP)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Storage engine, defaults to the one already in use or kvs
+    #[arg(long, value_enum, global = true, env = "KVS_ENGINE")]
+    engine: Option<Engine>,
+    /// Data directory, defaults to the current directory
+    #[arg(long, global = true, env = "KVS_PATH")]
+    path: Option<PathBuf>,
+    /// Size in bytes of the log after which the kvs engine compacts it
+    #[arg(long, global = true, env = "KVS_THRESHOLD")]
+    threshold: Option<u64>,
+    /// How the results of set, get and rm are printed
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    format: Output,
+}
+
+// Where the defaults come from, for containers that configure through the environment
+const ENV_HELP: &str = "The engine, path and threshold can also be given through the KVS_ENGINE, \
+KVS_PATH and KVS_THRESHOLD environment variables. A flag takes precedence over its \
+variable, which takes precedence over the default.";
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Output {
+    /// Bare values, for people
+    Text,
+    /// One JSON object per command, for scripts
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Set { k: String, v: String },
-    Get { k: String },
-    Rm { k: String },
+    Set {
+        k: String,
+        v: String,
+    },
+    Get {
+        k: String,
+        /// Printed in place of the value when the key is missing, in JSON with
+        /// `"found": false`
+        #[arg(long)]
+        default: Option<String>,
+    },
+    Rm {
+        k: String,
+    },
+    /// Write every key/value pair to a JSON file
+    Export {
+        file: PathBuf,
+    },
+    /// Set every key/value pair of a JSON file written by export
+    Import {
+        file: PathBuf,
+    },
+    /// Print every record of the log with its segment and offset
+    Dump,
 }
-fn main() -> Result<()> {
+fn main() {
     let cli = Cli::parse();
+    if let Err(e) = execute(cli) {
+        eprintln!("{}", e);
+        exit(exit_code(&e));
+    }
+}
+
+// Removing a missing key is told apart from other failures
+fn exit_code(e: &KvsError) -> i32 {
+    match e {
+        KvsError::KeyNotFound => 2,
+        _ => 1,
+    }
+}
 
-    let log_path = env::current_dir()?;
+fn execute(cli: Cli) -> Result<()> {
+    let log_path = match &cli.path {
+        Some(path) => path.clone(),
+        None => env::current_dir()?,
+    };
     let p = Path::new(&log_path);
-    let mut store = KvStore::open(p)?;
 
-    match &cli.command {
+    // reads the log as it is on disk, without opening the store
+    if let Some(Commands::Dump) = cli.command {
+        return KvStore::dump(p, io::stdout().lock());
+    }
+
+    let engine = resolve_engine(p, cli.engine.map(|e| e.name()))?;
+    let engine = Engine::from_str(&engine, false).map_err(|_| KvsError::WrongEngine(engine))?;
+
+    match engine {
+        Engine::Kvs => {
+            let mut config = KvStoreConfig::new();
+            if let Some(threshold) = cli.threshold {
+                config = config.threshold(Some(threshold));
+            }
+            let mut store = KvStore::open_with(p, config)?;
+            match &cli.command {
+                Some(Commands::Export { file }) => store.export(File::create(file)?),
+                Some(Commands::Import { file }) => store.import(File::open(file)?),
+                command => run(&mut store, command, cli.format),
+            }
+        }
+        Engine::Sled => run(&mut SledKvsEngine::open(p)?, &cli.command, cli.format),
+    }
+}
+
+fn run<E: KvsEngine>(store: &mut E, command: &Option<Commands>, format: Output) -> Result<()> {
+    match command {
         Some(Commands::Set { k, v }) => {
             store.set(k.to_string(), v.to_string())?;
-            ()
+            if format == Output::Json {
+                println!("{}", json!({ "key": k, "value": v }));
+            }
         }
-        Some(Commands::Get { k }) => {
+        Some(Commands::Get { k, default }) => {
             let v = store.get(k.to_string())?;
-            match v {
-                Some(v) => println!("{}", v),
-                None => println!("Key not found"),
+            let found = v.is_some();
+            match (v.or_else(|| default.clone()), format) {
+                // a default is never null, whether the key was there is told apart
+                (v, Output::Json) if default.is_some() => {
+                    println!("{}", json!({ "key": k, "value": v, "found": found }))
+                }
+                (v, Output::Json) => println!("{}", json!({ "key": k, "value": v })),
+                (Some(v), Output::Text) => println!("{}", v),
+                (None, Output::Text) => println!("Key not found"),
             }
-            ()
         }
-        Some(Commands::Rm { k }) => match store.remove(k.to_string()) {
-            Ok(_) => (),
-            Err(e) => {
-                println!("Key not found");
-                return Err(e);
+        Some(Commands::Rm { k }) => {
+            store.remove(k.to_string())?;
+            if format == Output::Json {
+                println!("{}", json!({ "key": k, "removed": true }));
             }
-        },
-        _ => return Err(KvsError::NoCommand),
+        }
+        // the kvs engine handles these before getting here
+        Some(Commands::Export { .. }) | Some(Commands::Import { .. }) | Some(Commands::Dump) => {
+            return Err(KvsError::Unsupported(Engine::Sled.name().to_owned()))
+        }
+        None => return Err(KvsError::NoCommand),
     }
-    // println!("{:?}", store);
     Ok(())
 }
@@ -0,0 +1,93 @@
+use crate::protocol::{self, Request, Response};
+use crate::{KvsError, Result};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+
+/// The `KvsClient` sends requests to a `KvsServer` over TCP.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClient, Result};
+/// # fn try_main() -> Result<()>{
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// let val = client.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to the server listening on the address.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Remove a given key.
+    ///
+    /// An error reported by the server, including a missing key, is returned as
+    /// `KvsError::ServerError`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Rm { key }).map(|_| ())
+    }
+
+    /// Sends the requests back to back without waiting on the server, then returns
+    /// its responses in the same order.
+    ///
+    /// A request that fails is answered with `Response::Err` and doesn't stop the
+    /// ones after it.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        let (reader, writer) = (&mut self.reader, &mut self.writer);
+        // requests are written on their own thread, a server blocked on sending
+        // responses nobody reads would stop reading requests
+        thread::scope(|scope| {
+            let sending = scope.spawn(move || -> Result<()> {
+                for request in requests {
+                    protocol::buffer_message(writer, request)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            let responses = (0..requests.len())
+                .map(|_| {
+                    protocol::read_message(reader)?
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+                })
+                .collect();
+            sending.join().expect("request thread panicked")?;
+            responses
+        })
+    }
+
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        protocol::write_message(&mut self.writer, &request)?;
+        match protocol::read_message(&mut self.reader)? {
+            Some(Response::Value(value)) => Ok(value),
+            Some(Response::Ok) => Ok(None),
+            Some(Response::Err(message)) => Err(KvsError::ServerError(message)),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time used to expire keys.
+///
+/// The store reads the time through this trait rather than the system clock
+/// directly, so expiry can be driven by hand in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time in seconds since the unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// The system clock, used unless a config supplies another.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
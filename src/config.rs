@@ -0,0 +1,251 @@
+use crate::{Clock, LogFormat, RecordCodec, SystemClock};
+use std::sync::Arc;
+
+/// Default size of the log in bytes before compaction is triggered
+const DEFAULT_THRESHOLD: u64 = 1024 * 1024;
+
+/// Default size of a log segment in bytes before appends roll over to a new one
+const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// When log writes are synced to the underlying storage device.
+///
+/// Every write is flushed to the operating system before returning regardless of
+/// the mode, so it survives the process crashing. Surviving a power loss or OS crash
+/// additionally requires the data to be synced to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Sync after every write, a write that returned `Ok` survives a power loss.
+    Always,
+    /// Never sync explicitly, the OS decides when data reaches the device.
+    Never,
+    /// Sync after every `n` writes, at most the last `n - 1` writes can be lost.
+    EveryN(u64),
+}
+
+/// When a log that outgrew the compaction threshold is compacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionMode {
+    /// Compact as part of the write that crossed the threshold.
+    Inline,
+    /// Leave it to the caller, who can check `KvStore::should_compact` and call
+    /// `KvStore::compact` off the hot path. Writes never block on a compaction.
+    Deferred,
+    /// Compact a bounded part of the log as part of each write, scanning about the
+    /// given number of bytes of records, until a pass over the log is complete.
+    /// A pass starts with the write that crossed the threshold, see
+    /// `KvStore::compact_step`.
+    Incremental(u64),
+}
+
+/// Options used when opening a `KvStore`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreConfig, Result};
+/// # use std::env;
+/// # fn try_main() -> Result<()>{
+/// let dir = env::current_dir()?;
+/// let config = KvStoreConfig::new().threshold(Some(64 * 1024));
+/// let mut store = KvStore::open_with(&dir, config)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    pub(crate) threshold: Option<u64>,
+    pub(crate) dead_ratio: Option<f64>,
+    pub(crate) compaction: CompactionMode,
+    pub(crate) segment_size: u64,
+    pub(crate) sync: SyncMode,
+    pub(crate) format: LogFormat,
+    pub(crate) compress: bool,
+    pub(crate) name: Option<String>,
+    pub(crate) read_only: bool,
+    pub(crate) background_writer: bool,
+    pub(crate) capacity: usize,
+    pub(crate) max_key_bytes: Option<usize>,
+    pub(crate) max_value_bytes: Option<usize>,
+    pub(crate) codec: Option<Arc<dyn RecordCodec>>,
+    pub(crate) redact_values: bool,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        Self {
+            threshold: Some(DEFAULT_THRESHOLD),
+            dead_ratio: None,
+            compaction: CompactionMode::Inline,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync: SyncMode::Never,
+            format: LogFormat::Bincode,
+            compress: false,
+            name: None,
+            read_only: false,
+            background_writer: false,
+            capacity: 0,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            codec: None,
+            redact_values: true,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl KvStoreConfig {
+    /// Creates a config with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size in bytes of all log segments together after which compaction is
+    /// triggered.
+    ///
+    /// `None` disables automatic compaction entirely.
+    pub fn threshold(mut self, threshold: Option<u64>) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the share of the log, between 0 and 1, that has to be taken up by
+    /// overwritten and removed records before a log past the threshold is compacted.
+    ///
+    /// A log of mostly live records then keeps growing instead of being rewritten for
+    /// little gain. `None`, the default, compacts on size alone.
+    pub fn dead_ratio(mut self, dead_ratio: Option<f64>) -> Self {
+        self.dead_ratio = dead_ratio;
+        self
+    }
+
+    /// Sets when a log past the threshold is compacted, defaults to
+    /// `CompactionMode::Inline`.
+    pub fn compaction(mut self, compaction: CompactionMode) -> Self {
+        self.compaction = compaction;
+        self
+    }
+
+    /// Sets the size in bytes after which appends roll over to a new log segment.
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Sets when writes are synced to the storage device, defaults to `SyncMode::Never`.
+    pub fn sync(mut self, sync: SyncMode) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Sets the format records are written in, defaults to `LogFormat::Bincode`.
+    ///
+    /// An existing log in another format is rewritten in this one when opened.
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the largest key in bytes a write accepts, `None`, the default, for no limit.
+    ///
+    /// A write of a larger key fails with `KvsError::KeyTooLarge` before anything is
+    /// written to the log.
+    pub fn max_key_bytes(mut self, max_key_bytes: Option<usize>) -> Self {
+        self.max_key_bytes = max_key_bytes;
+        self
+    }
+
+    /// Sets the largest value in bytes a write accepts, `None`, the default, for no
+    /// limit.
+    ///
+    /// A write of a larger value fails with `KvsError::ValueTooLarge` before anything
+    /// is written to the log, a value streamed by `KvStore::set_from_reader` as soon
+    /// as it passes the limit.
+    pub fn max_value_bytes(mut self, max_value_bytes: Option<usize>) -> Self {
+        self.max_value_bytes = max_value_bytes;
+        self
+    }
+
+    /// Sets a codec that encodes the records in place of the format, see
+    /// `RecordCodec`.
+    ///
+    /// A log of built in records is rewritten through the codec when opened, a log
+    /// written through a codec fails to open without one.
+    pub fn codec(mut self, codec: Arc<dyn RecordCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Sets whether compaction compresses the segment it writes with zstd, defaults
+    /// to `false`.
+    ///
+    /// Each record is compressed on its own so it can still be read directly, which
+    /// pays off for larger values. The segment being appended to is never compressed,
+    /// and compressed segments stay readable after turning this off again.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Sets the name of the log, so that several stores can share a directory.
+    ///
+    /// The segments of a named log are called `{name}.{id}.log`, those of the
+    /// default unnamed log `{id}.log`. Only the unnamed log picks up a `log.txt`
+    /// written by older versions.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets whether the store is opened for reads only, defaults to `false`.
+    ///
+    /// A read-only store opens its log without write access and rejects every write
+    /// with `KvsError::ReadOnly`, compactions included. Several read-only stores can
+    /// share a log, but not with a store that writes.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether appends are written to the log by a background thread, defaults
+    /// to `false`.
+    ///
+    /// A write then returns as soon as its record is queued for the thread, reads see
+    /// it right away all the same. The sync mode applies to each batch of records the
+    /// thread writes out, `KvStore::flush` waits for the queue to be written out and
+    /// synced. A record that fails to be written fails the next flush, and every
+    /// write after it.
+    pub fn background_writer(mut self, background_writer: bool) -> Self {
+        self.background_writer = background_writer;
+        self
+    }
+
+    /// Sets whether values are kept out of the operation logging of the store,
+    /// defaults to true.
+    ///
+    /// Sets, reads and removals are logged along with their keys at the debug level.
+    /// With redaction off the values set and read are logged too, which leaks
+    /// whatever secrets they hold into the logs. The `Debug` output of a `KvStore`
+    /// never shows values either way.
+    pub fn redact_values(mut self, redact_values: bool) -> Self {
+        self.redact_values = redact_values;
+        self
+    }
+
+    /// Sets the number of keys to size the index for while it is rebuilt on open,
+    /// defaults to 0.
+    ///
+    /// The index is sized for an estimate of the records in the log either way, a
+    /// larger hint saves growing it again and again when loading that many keys.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets the clock keys with a time to live are expired against, defaults to
+    /// `SystemClock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
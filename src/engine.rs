@@ -1,2 +1,89 @@
+use crate::{KvsError, Result};
+use std::{fs, io, path::Path};
+
+// File recording which engine a data directory belongs to
+const ENGINE_MARKER: &str = "engine";
+
 /// Trait to define the interfaces to Key Value engines
-pub trait KvsEngine {}
+pub trait KvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist. An engine may keep read state
+    /// in each handle, so readers on several threads each use a handle of their own.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Remove a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Makes every write that already returned `Ok` durable on disk.
+    ///
+    /// Engines that write everything through before returning keep the default,
+    /// which does nothing.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Record the engine in the marker file of the directory on first use, failing with
+// `KvsError::WrongEngine` if another engine recorded itself before. With `claim`
+// false a missing marker is left missing.
+pub(crate) fn check_engine(dir: &Path, engine: &str, claim: bool) -> Result<()> {
+    let marker = dir.join(ENGINE_MARKER);
+    match fs::read_to_string(&marker) {
+        Ok(recorded) if recorded.trim() == engine => Ok(()),
+        Ok(recorded) => Err(KvsError::WrongEngine(recorded.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if claim {
+                fs::create_dir_all(dir)?;
+                fs::write(marker, engine)?;
+            }
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the name of the engine whose data is already in the directory, if any.
+///
+/// Engines record themselves in a marker file on first use. A directory written
+/// before the marker existed is recognized by its files: log segments or a
+/// `log.txt` for `kvs`, a `db` for `sled`.
+pub fn existing_engine(dir: &Path) -> Option<String> {
+    if let Ok(recorded) = fs::read_to_string(dir.join(ENGINE_MARKER)) {
+        return Some(recorded.trim().to_owned());
+    }
+    let segments = fs::read_dir(dir).is_ok_and(|mut entries| {
+        entries.any(|entry| {
+            entry.is_ok_and(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        })
+    });
+    if segments || dir.join("log.txt").exists() {
+        Some("kvs".to_owned())
+    } else if dir.join("db").exists() {
+        Some("sled".to_owned())
+    } else {
+        None
+    }
+}
+
+/// Returns the engine to open the directory with: the requested one, or else the one
+/// already in use, or else `kvs`.
+///
+/// Fails with `KvsError::WrongEngine` if the directory holds the data of another
+/// engine than the requested one.
+pub fn resolve_engine(dir: &Path, requested: Option<&str>) -> Result<String> {
+    match (requested, existing_engine(dir)) {
+        (Some(requested), Some(existing)) if requested != existing => {
+            Err(KvsError::WrongEngine(existing))
+        }
+        (Some(requested), _) => Ok(requested.to_owned()),
+        (None, existing) => Ok(existing.unwrap_or_else(|| "kvs".to_owned())),
+    }
+}
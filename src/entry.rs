@@ -0,0 +1,88 @@
+use crate::{KvStore, Result};
+use std::fmt;
+
+// Change made to the value of a present key, see `Entry::and_modify`
+pub(crate) type Modify<'a> = Box<dyn FnOnce(&mut String) + 'a>;
+
+/// A key of a `KvStore` to read and write back in place, made by `KvStore::entry`.
+///
+/// Nothing is read until the entry is resolved by `or_insert` or `or_insert_with`,
+/// which read the current value and write the result through the log while other
+/// writes wait.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()>{
+/// let mut store = KvStore::open_in_memory()?;
+/// let visits = |store: &mut KvStore| {
+///     store
+///         .entry("visits".to_owned())
+///         .and_modify(|count| *count = (count.parse::<u64>().unwrap() + 1).to_string())
+///         .or_insert("1".to_owned())
+/// };
+/// assert_eq!(visits(&mut store)?, "1");
+/// assert_eq!(visits(&mut store)?, "2");
+/// # Ok(())
+/// # }
+/// ```
+#[must_use = "an entry does nothing until resolved by one of its `or_insert` methods"]
+pub struct Entry<'a> {
+    store: &'a mut KvStore,
+    key: String,
+    modify: Option<Modify<'a>>,
+}
+
+// the value is only ever read on resolve, so there is none to leave out here
+impl fmt::Debug for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("store", &self.store)
+            .field("key", &self.key)
+            .field("modify", &self.modify.is_some())
+            .finish()
+    }
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, key: String) -> Self {
+        Entry {
+            store,
+            key,
+            modify: None,
+        }
+    }
+
+    /// The key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Changes the value in place if the key is present once the entry is resolved,
+    /// after any change given before.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut String) + 'a) -> Self {
+        self.modify = Some(match self.modify.take() {
+            Some(before) => Box::new(move |value: &mut String| {
+                before(value);
+                f(value)
+            }),
+            None => Box::new(f),
+        });
+        self
+    }
+
+    /// Returns the value of the key, modified if `and_modify` was called, setting it
+    /// to `default` if the key is absent.
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the key, modified if `and_modify` was called, setting it
+    /// to the result of `default` if the key is absent.
+    ///
+    /// `default` only runs on a miss.
+    pub fn or_insert_with(self, default: impl FnOnce() -> String) -> Result<String> {
+        self.store.resolve(self.key, self.modify, default)
+    }
+}
@@ -1,24 +1,120 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
-/// Wrapper error
+/// Error type shared by the library, the engines and the binaries
 pub enum KvsError {
-    #[error("Failed to read file")]
-    /// Failure to read log file
-    FileReadError(#[from] std::io::Error),
-    #[error("Failed to parse file")]
+    #[error("IO error: {0}")]
+    /// Failure to read or write a file
+    IoError(#[from] std::io::Error),
+    #[error("Failed to parse log: {0}")]
     /// Failure to parse / deserialize log file
     ParseError(#[from] serde_json::Error),
-    #[error("KeyNotFound")]
+    #[error("Failed to decode record: {0}")]
+    /// Failure to serialize / deserialize a `bincode` record
+    BincodeError(#[from] bincode::Error),
+    #[error("Unsupported log: {0}")]
+    /// The log header is damaged or from an unknown version
+    UnsupportedLog(String),
+    #[error("Checksum mismatch in log segment {segment} at offset {offset}")]
+    /// A record of the log doesn't match its checksum
+    ChecksumMismatch {
+        /// Segment holding the damaged record
+        segment: u64,
+        /// Offset of the damaged record within the segment
+        offset: u64,
+    },
+    #[error("Failed to read key {key} at offset {offset} of log segment {segment}: {source}")]
+    /// The record holding the value of a key couldn't be read
+    ReadFailed {
+        /// Key whose value was being read
+        key: String,
+        /// Segment holding the record
+        segment: u64,
+        /// Offset of the record within the segment
+        offset: u64,
+        /// What went wrong reading the record
+        source: Box<KvsError>,
+    },
+    #[error(
+        "Failed to replay record {record} of log segment {segment} at offset {offset}: {source}"
+    )]
+    /// A record of the log couldn't be read or parsed while replaying the log
+    ReplayFailed {
+        /// Segment holding the record, 0 for a log written before segments
+        segment: u64,
+        /// Offset of the record within the segment
+        offset: u64,
+        /// Index of the record within the segment, counting from 0
+        record: u64,
+        /// What went wrong reading the record
+        source: Box<KvsError>,
+    },
+    #[error("Index points at the wrong record: {0}")]
+    /// The record at a position of the index doesn't hold the key it was looked up for
+    MisplacedRecord(String),
+    #[error("Value of {actual} bytes is larger than the limit of {limit} bytes")]
+    /// A value is larger than the configured limit, or doesn't fit in a single
+    /// record of the log
+    ValueTooLarge {
+        /// Largest value in bytes that is accepted
+        limit: u64,
+        /// Size of the value in bytes
+        actual: u64,
+    },
+    #[error("Key of {actual} bytes is larger than the limit of {limit} bytes")]
+    /// A key is larger than the configured limit
+    KeyTooLarge {
+        /// Largest key in bytes that is accepted
+        limit: u64,
+        /// Size of the key in bytes
+        actual: u64,
+    },
+    #[error("Log is already open in another store")]
+    /// Another store, possibly in another process, holds the lock on the log
+    AlreadyLocked,
+    #[error("Value of key {0} is not an integer")]
+    /// A counter operation found a value that doesn't parse as an `i64`
+    NotAnInteger(String),
+    #[error("Counter {0} would overflow")]
+    /// A counter operation would take the value out of the range of an `i64`
+    IntegerOverflow(String),
+    #[error("Store was opened read-only")]
+    /// A write was attempted through a store opened read-only
+    ReadOnly,
+    #[error("Sled error: {0}")]
+    /// Failure inside the `sled` engine
+    SledError(#[from] sled::Error),
+    #[error("Invalid UTF-8 value")]
+    /// A stored value was not valid UTF-8
+    Utf8Error(#[from] std::string::FromUtf8Error),
+    #[error("Key not found")]
     /// Attempted to remove key that was never present
     KeyNotFound,
     #[error("Unknown error occured")]
     /// Something terrible has happened
     Unknown,
     #[error("No command specified")]
-    /// No command was provided
+    /// No command was provided to a binary
     NoCommand,
+    #[error("Unsupported protocol version {0}")]
+    /// A message of a protocol version this crate doesn't speak
+    ProtocolVersion(u8),
+    #[error("{0}")]
+    /// Error reported by the server in response to a request
+    ServerError(String),
+    #[error("Data directory was previously used by the {0} engine")]
+    /// The selected engine differs from the one that created the data
+    WrongEngine(String),
+    #[error("Not supported by the {0} engine")]
+    /// The operation is only available on another engine
+    Unsupported(String),
+    #[error("Store was created with {0} shards")]
+    /// A sharded store was opened with another number of shards than it was created with
+    ShardCount(usize),
+    #[error("Thread pool needs at least one thread")]
+    /// A thread pool was created without any threads to run its jobs
+    NoThreads,
 }
 
-/// Type alias
+/// Result type using `KvsError` for all fallible operations in the crate
 pub type Result<T> = std::result::Result<T, KvsError>;
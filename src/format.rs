@@ -0,0 +1,344 @@
+use crate::storage::Storage;
+use crate::wal::Commands;
+use crate::{KvsError, LogRecord, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+// Every log starts with a header of the magic bytes, the layout version
+// and the record format, followed by length prefixed records
+const MAGIC: &[u8; 3] = b"KVS";
+const VERSION: u8 = 2;
+// Version whose records had no checksum
+const UNCHECKED_VERSION: u8 = 1;
+pub(crate) const HEADER_LEN: usize = 5;
+// Prefix of a record, its length then the CRC32 of its payload, little-endian
+pub(crate) const PREFIX_LEN: usize = 8;
+// Prefix of a record without a checksum
+pub(crate) const UNCHECKED_PREFIX_LEN: usize = 4;
+// Set in the format tag of a segment whose payloads are compressed with zstd
+const COMPRESSED: u8 = 0x80;
+// Format tag of a segment whose records were encoded by a `RecordCodec`
+const CUSTOM: u8 = 0x7f;
+
+/// Serialization format of the records in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable `serde_json` records.
+    Json,
+    /// Compact `bincode` records, faster to write and to replay.
+    Bincode,
+}
+
+impl LogFormat {
+    fn tag(self) -> u8 {
+        match self {
+            LogFormat::Json => 0,
+            LogFormat::Bincode => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(LogFormat::Json),
+            1 => Ok(LogFormat::Bincode),
+            _ => Err(KvsError::UnsupportedLog(format!(
+                "unknown record format {}",
+                tag
+            ))),
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            LogFormat::Json => Ok(serde_json::to_vec(value)?),
+            LogFormat::Bincode => Ok(bincode::serialize(value)?),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T> {
+        match self {
+            LogFormat::Json => Ok(serde_json::from_slice(buf)?),
+            LogFormat::Bincode => Ok(bincode::deserialize(buf)?),
+        }
+    }
+}
+
+/// Encodes the records of the log in a format of its own, in place of `LogFormat`.
+///
+/// A store opened with `KvStoreConfig::codec` writes every record through the codec,
+/// and has to be opened with a codec again to read them back. The payload is framed
+/// and checksummed like any other, and compressed after encoding if asked to.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreConfig, KvsEngine, KvsError, LogRecord, RecordCodec, Result};
+/// # use std::sync::Arc;
+/// # use tempfile::TempDir;
+/// #[derive(Debug)]
+/// struct Lines;
+///
+/// impl RecordCodec for Lines {
+///     fn encode(&self, record: &LogRecord) -> Result<Vec<u8>> {
+///         match record {
+///             LogRecord::Set(key, value) => Ok(format!("SET {}\n{}", key, value).into_bytes()),
+///             LogRecord::Remove(key) => Ok(format!("RM {}", key).into_bytes()),
+///             other => Err(KvsError::UnsupportedLog(other.to_string())),
+///         }
+///     }
+///
+///     fn decode(&self, payload: &[u8]) -> Result<LogRecord> {
+///         let line = String::from_utf8(payload.to_vec())?;
+///         if let Some(key) = line.strip_prefix("RM ") {
+///             return Ok(LogRecord::Remove(key.to_owned()));
+///         }
+///         match line.strip_prefix("SET ").and_then(|set| set.split_once('\n')) {
+///             Some((key, value)) => Ok(LogRecord::Set(key.to_owned(), value.to_owned())),
+///             None => Err(KvsError::UnsupportedLog(line)),
+///         }
+///     }
+/// }
+///
+/// # fn try_main() -> Result<()> {
+/// # let dir = TempDir::new()?;
+/// let config = KvStoreConfig::new().codec(Arc::new(Lines));
+/// let mut store = KvStore::open_with(dir.path(), config.clone())?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// drop(store);
+///
+/// let mut store = KvStore::open_with(dir.path(), config)?;
+/// assert_eq!(store.get("key")?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+pub trait RecordCodec: Debug + Send + Sync {
+    /// Encodes a record into the payload of its frame in the log.
+    fn encode(&self, record: &LogRecord) -> Result<Vec<u8>>;
+
+    /// Decodes a payload written by `encode` back into its record.
+    fn decode(&self, payload: &[u8]) -> Result<LogRecord>;
+}
+
+// How the payloads of a segment are encoded
+#[derive(Debug, Clone)]
+pub(crate) struct Codec {
+    pub(crate) format: LogFormat,
+    pub(crate) compressed: bool,
+    pub(crate) custom: Option<Arc<dyn RecordCodec>>, // encodes in place of the format
+}
+
+impl Codec {
+    pub(crate) fn new(
+        format: LogFormat,
+        compressed: bool,
+        custom: Option<Arc<dyn RecordCodec>>,
+    ) -> Self {
+        Self {
+            format,
+            compressed,
+            custom,
+        }
+    }
+
+    pub(crate) fn encode(&self, command: &Commands) -> Result<Vec<u8>> {
+        let payload = match &self.custom {
+            Some(custom) => custom.encode(&command.to_record())?,
+            None => self.format.encode(command)?,
+        };
+        if self.compressed {
+            Ok(zstd::encode_all(payload.as_slice(), 0)?)
+        } else {
+            Ok(payload)
+        }
+    }
+
+    pub(crate) fn decode(&self, payload: &[u8]) -> Result<Commands> {
+        if self.compressed {
+            self.decode_plain(&zstd::decode_all(payload)?)
+        } else {
+            self.decode_plain(payload)
+        }
+    }
+
+    fn decode_plain(&self, payload: &[u8]) -> Result<Commands> {
+        match &self.custom {
+            Some(custom) => Ok(Commands::from_record(custom.decode(payload)?)),
+            None => self.format.decode(payload),
+        }
+    }
+
+    // True if the records are encoded the same way, compressed or not
+    pub(crate) fn same_records(&self, other: &Codec) -> bool {
+        match (&self.custom, &other.custom) {
+            (None, None) => self.format == other.format,
+            (custom, other) => custom.is_some() && other.is_some(),
+        }
+    }
+
+    // True for uncompressed bincode, whose values can be read in place
+    pub(crate) fn streams_values(&self) -> bool {
+        self.custom.is_none() && self.format == LogFormat::Bincode && !self.compressed
+    }
+
+    pub(crate) fn header(&self) -> [u8; HEADER_LEN] {
+        let mut tag = match self.custom {
+            Some(_) => CUSTOM,
+            None => self.format.tag(),
+        };
+        if self.compressed {
+            tag |= COMPRESSED;
+        }
+        [MAGIC[0], MAGIC[1], MAGIC[2], VERSION, tag]
+    }
+}
+
+// What was found on disk when opening a log
+pub(crate) enum Layout {
+    // missing or empty, a header still has to be written
+    Empty,
+    // bare json records written before the header was introduced
+    Legacy,
+    // length prefixed records without a checksum
+    Unchecked(LogFormat),
+    Framed(Codec),
+}
+
+// Segments written by a `RecordCodec` are only read with `custom` given
+pub(crate) fn detect(
+    storage: &Storage,
+    path: &Path,
+    custom: Option<&Arc<dyn RecordCodec>>,
+) -> Result<Layout> {
+    let mut f = match storage.open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Layout::Empty),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    f.by_ref()
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+
+    if header.is_empty() {
+        Ok(Layout::Empty)
+    } else if !header.starts_with(MAGIC) {
+        Ok(Layout::Legacy)
+    } else if header.len() < HEADER_LEN {
+        Err(KvsError::UnsupportedLog("truncated header".to_owned()))
+    } else if header[3] == UNCHECKED_VERSION {
+        Ok(Layout::Unchecked(LogFormat::from_tag(header[4])?))
+    } else if header[3] != VERSION {
+        Err(KvsError::UnsupportedLog(format!(
+            "unknown version {}",
+            header[3]
+        )))
+    } else if header[4] & !COMPRESSED == CUSTOM {
+        let custom = custom.ok_or_else(|| {
+            KvsError::UnsupportedLog("written with a custom record codec".to_owned())
+        })?;
+        Ok(Layout::Framed(Codec::new(
+            LogFormat::Bincode,
+            header[4] & COMPRESSED != 0,
+            Some(custom.clone()),
+        )))
+    } else {
+        let format = LogFormat::from_tag(header[4] & !COMPRESSED)?;
+        Ok(Layout::Framed(Codec::new(
+            format,
+            header[4] & COMPRESSED != 0,
+            None,
+        )))
+    }
+}
+
+// Prefix the payload with its length and checksum
+pub(crate) fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(PREFIX_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// Read the payload of the next frame, `None` at the end of the log.
+// A frame cut short is an error rather than the end of the log, as is one
+// whose payload doesn't match its checksum. The segment and offset of the
+// frame are only used to report a mismatch.
+pub(crate) fn read_frame(
+    reader: &mut impl Read,
+    segment: u64,
+    offset: u64,
+) -> Result<Option<Vec<u8>>> {
+    let mut prefix = [0; PREFIX_LEN];
+    if !read_prefix(reader, &mut prefix)? {
+        return Ok(None);
+    }
+    let (len, checksum) = prefix.split_at(UNCHECKED_PREFIX_LEN);
+    let payload = read_payload(reader, len)?;
+    if crc32fast::hash(&payload).to_le_bytes() != checksum {
+        return Err(KvsError::ChecksumMismatch { segment, offset });
+    }
+    Ok(Some(payload))
+}
+
+// The payload of a frame read into memory whole, `None` if the bytes are cut short
+// or run past the frame. Like `read_frame` it is an error for the payload not to
+// match its checksum.
+pub(crate) fn frame_payload(frame: &[u8], segment: u64, offset: u64) -> Result<Option<&[u8]>> {
+    let Some((prefix, payload)) = frame.split_at_checked(PREFIX_LEN) else {
+        return Ok(None);
+    };
+    let (len, checksum) = prefix.split_at(UNCHECKED_PREFIX_LEN);
+    let mut bytes = [0; UNCHECKED_PREFIX_LEN];
+    bytes.copy_from_slice(len);
+    if u32::from_le_bytes(bytes) as usize != payload.len() {
+        return Ok(None);
+    }
+    if crc32fast::hash(payload).to_le_bytes() != checksum {
+        return Err(KvsError::ChecksumMismatch { segment, offset });
+    }
+    Ok(Some(payload))
+}
+
+// Read the payload of the next frame written without a checksum
+pub(crate) fn read_unchecked_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut prefix = [0; UNCHECKED_PREFIX_LEN];
+    if !read_prefix(reader, &mut prefix)? {
+        return Ok(None);
+    }
+    Ok(Some(read_payload(reader, &prefix)?))
+}
+
+// Fill the prefix, false if the log ended cleanly before it
+fn read_prefix(reader: &mut impl Read, prefix: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < prefix.len() {
+        match reader.read(&mut prefix[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            // retried as `read_exact` would
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn read_payload(reader: &mut impl Read, len: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = [0; UNCHECKED_PREFIX_LEN];
+    bytes.copy_from_slice(len);
+    let len = u32::from_le_bytes(bytes) as u64;
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(payload)
+}
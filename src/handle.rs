@@ -0,0 +1,19 @@
+/// Where the value of a key is stored in the log, as returned by
+/// `KvStore::try_get_cached`.
+///
+/// Taking a handle only consults the in-memory index, `KvStore::read_handle` does
+/// the disk read. A handle keeps pointing at the record it was taken for, the key
+/// may have been written again since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueHandle {
+    /// Log segment holding the record of the value.
+    pub segment: u64,
+    /// Offset of the record within the segment.
+    pub offset: u64,
+    /// Length in bytes of the record, framing included.
+    pub len: usize,
+    /// Length in bytes of the value itself, see `KvStore::value_len`.
+    pub value_len: usize,
+    /// Unix seconds after which the value expires, if it has a time to live.
+    pub expires: Option<u64>,
+}
@@ -1,249 +1,1570 @@
-use serde::{Deserialize, Serialize};
+use crate::engine;
+use crate::entry::{self, Modify};
+use crate::format::{Layout, HEADER_LEN};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::repair::{self, RepairMode, RepairReport};
+use crate::storage::Storage;
+use crate::txn::TxnBatch;
+use crate::verify::{self, VerifyReport};
+use crate::wal::{
+    self, Commands, Cursor, Index, LogReader, Position, Records, Segments, Tail, Value, Wal,
+};
+use crate::watch::ChangeEvent;
+use crossbeam_skiplist::{map::Entry, SkipMap};
+use crossbeam_utils::atomic::AtomicCell;
 
-use crate::{KvsError, Result};
+use crate::{Clock, KvStats, KvStoreConfig, KvsEngine, KvsError, LogRecord, Result, ValueHandle};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    path::Path,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, Read, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, mpsc::Receiver, Arc, Mutex, PoisonError},
+    time::Duration,
 };
+
+// The index readers consult without locking. A key that is written again has
+// its position swapped in place, replacing the entry would briefly hide it.
+type Positions = SkipMap<Vec<u8>, AtomicCell<Position>>;
+
+// Records `merge` appends before flushing them and updating the index
+const MERGE_BATCH: usize = 1024;
+
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+/// Keys and values of arbitrary bytes are stored through `set_bytes` and the
+/// other `_bytes` methods instead. The string methods see the same keys, a value
+/// that isn't valid UTF-8 fails to read as a string.
+///
+/// Key/value pairs are persisted to a log on disk, split into numbered segments, with
+/// an index of record positions kept in memory. Cloning a `KvStore` yields another
+/// handle to the same store, which can be moved to another thread. Reads through
+/// different handles never block each other or a write in progress, writes are
+/// serialized.
 ///
 /// Example:
 ///
 /// ```rust
-/// # use kvs::{KvStore,Result};
-/// # use std::env;
+/// # use kvs::{KvStore,KvsEngine,Result};
 /// # fn try_main() -> Result<()>{
-/// let dir = env::current_dir()?;
-/// let mut store = KvStore::open(&dir)?;
+/// let mut store = KvStore::open("data")?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Debug)]
-pub struct KvStore<'a> {
-    map: HashMap<String, (usize, usize)>, // This will be the index
-    wal: WAL<'a>,                         // WAL
-    final_offset: usize,                  //EOF byte
+#[derive(Clone)]
+pub struct KvStore {
+    map: Arc<Positions>,   // This will be the index, shared by all handles
+    wal: Arc<Mutex<Wal>>,  // WAL, single writer shared by all handles
+    reader: LogReader,     // read handle owned by this handle
+    clock: Arc<dyn Clock>, // keys with a time to live expire against this
+    redact_values: bool,   // values read are kept out of the operation logging
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>, // timings of the operations, shared with the WAL
 }
 
-#[derive(Debug)]
-struct WAL<'a> {
-    size: u128, // current size of WAL
-    /// Line limit for log file before compaction should occur
-    threshold: u128, // currently this is number of lines, but should rather by size on disk
-    // handle: Option<File>, // opened file handle
-    path: &'a Path,
-    file: &'a str,
-}
+impl KvStore {
+    /// Creates a `KvStore`, opening the log for appends.
+    pub fn new(p: impl AsRef<Path>) -> Result<Self> {
+        KvStore::with_config(p.as_ref(), KvStoreConfig::default(), Storage::Disk)
+    }
 
-impl<'a> WAL<'a> {
-    fn new(path: &'a Path, file: &'a str) -> Self {
-        Self {
-            size: 0,
-            threshold: 100,
-            path,
-            file,
-        }
+    fn with_config(p: &Path, config: KvStoreConfig, storage: Storage) -> Result<Self> {
+        let oldest = Arc::new(AtomicU64::new(0));
+        let pending = Arc::new(SkipMap::new());
+        let segments = Segments::new(p, config.name.clone(), config.codec.clone(), storage);
+        let wal = Wal::new(segments.clone(), &config, oldest.clone(), pending.clone())?;
+        Ok(KvStore {
+            map: Arc::new(SkipMap::new()),
+            #[cfg(feature = "metrics")]
+            metrics: wal.metrics.clone(),
+            wal: Arc::new(Mutex::new(wal)),
+            reader: LogReader::new(segments, oldest, pending),
+            clock: config.clock,
+            redact_values: config.redact_values,
+        })
     }
 
-    // overwrite the existing log with an empty file
-    fn clear(&self) -> Result<()> {
-        File::create(self.path.join(self.file))?;
-        Ok(())
+    /// Returns every live key in the store, in no particular order.
+    ///
+    /// Only the in-memory index is consulted, the log is not read. Keys that aren't
+    /// valid UTF-8 are left out.
+    pub fn keys(&self) -> Vec<String> {
+        let now = self.clock.now();
+        self.map
+            .iter()
+            .filter(|entry| !entry.value().load().expired(now))
+            .filter_map(|entry| String::from_utf8(entry.key().clone()).ok())
+            .collect()
     }
 
-    // Stream read the log into a vector of commands
-    fn stream(&self) -> Result<Vec<Commands>> {
-        let f = File::open(self.path.join(self.file))?;
-        let commands = serde_json::Deserializer::from_reader(&f)
-            .into_iter::<Commands>()
-            .map(|c| c.unwrap())
-            .collect::<Vec<Commands>>();
-        Ok(commands)
+    /// Returns up to `limit` live keys in sorted order, starting after the key `after`
+    /// or from the first key if it is `None`.
+    ///
+    /// Passing the last key of a page as `after` returns the next page, a key removed
+    /// in the meantime doesn't have to exist for that. Keys that aren't valid UTF-8
+    /// are left out.
+    pub fn keys_paged(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let now = self.clock.now();
+        let start = match after {
+            Some(after) => Bound::Excluded(after.as_bytes()),
+            None => Bound::Unbounded,
+        };
+        self.map
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .filter(|entry| !entry.value().load().expired(now))
+            .filter_map(|entry| String::from_utf8(entry.key().clone()).ok())
+            .take(limit)
+            .collect()
     }
 
-    // Read one command based off its position in the log
-    fn read_one(&self, offsets: (usize, usize)) -> Result<Commands> {
-        let mut handle = OpenOptions::new()
-            .read(true)
-            .open(self.path.join(self.file))?;
+    /// Returns the number of live keys in the store.
+    pub fn len(&self) -> usize {
+        let now = self.clock.now();
+        self.map
+            .iter()
+            .filter(|entry| !entry.value().load().expired(now))
+            .count()
+    }
+
+    /// Returns `true` if the store holds no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the store holds a value for the key.
+    ///
+    /// Unlike `get`, only the in-memory index is consulted, the value is not read.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let now = self.clock.now();
+        self.map
+            .get(key.as_bytes())
+            .is_some_and(|entry| !entry.value().load().expired(now))
+    }
 
-        let mut buf = vec![0; offsets.1 - offsets.0];
-        handle.seek(SeekFrom::Start(offsets.0 as u64))?;
-        handle.read_exact(&mut buf)?;
+    /// Returns the length in bytes of the value of a key, `None` if it isn't set.
+    ///
+    /// The length is kept in the index, so the value is not read. It counts the
+    /// bytes of the value alone, not the record around it in the log.
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let now = self.clock.now();
+        let position = self.map.get(key.as_bytes())?.value().load();
+        (!position.expired(now)).then_some(position.value_len)
+    }
 
-        let command: Commands = serde_json::from_slice(&buf).unwrap();
+    /// Returns an iterator over every live key/value pair, in no particular order.
+    ///
+    /// The keys are collected up front while each value is read from the log as the
+    /// iterator advances, so a key removed in the meantime is skipped. A value that
+    /// can't be read, or isn't valid UTF-8, is yielded as an error.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let mut reader = self.reader.clone();
+        self.keys().into_iter().filter_map(move |key| {
+            match Self::lookup_str(&self.map, &mut reader, &key, self.clock.now()) {
+                Ok(value) => value.map(|v| Ok((key, v))),
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
 
-        Ok(command)
+    /// Returns an iterator over the live key/value pairs whose key falls in the range,
+    /// in sorted order.
+    ///
+    /// The index is kept sorted, so only the keys in the range are visited. Each value
+    /// is read from the log as the iterator advances, a value that can't be read is
+    /// yielded as an error.
+    pub fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<String> + 'a,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a {
+        // strings sort the same as their bytes
+        let bounds = (
+            range.start_bound().map(|start| start.as_bytes().to_vec()),
+            range.end_bound().map(|end| end.as_bytes().to_vec()),
+        );
+        self.read_entries(self.map.range(bounds))
     }
 
-    // append some serialized data to the log
-    fn append(&mut self, data: String) -> Result<usize> {
-        let mut handle = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(self.path.join(self.file))?;
-        let num_bytes = handle.write(data.as_bytes())?;
-        self.size += 1;
-        Ok(num_bytes)
+    /// Returns an iterator over the live key/value pairs whose key starts with
+    /// `prefix`, in sorted order.
+    ///
+    /// The scan starts at the first key with the prefix and stops at the first one
+    /// without it, values are read as in `range`.
+    pub fn prefix_scan<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a {
+        let entries = self
+            .map
+            .range::<[u8], _>((Bound::Included(prefix.as_bytes()), Bound::Unbounded))
+            .take_while(move |entry| entry.key().starts_with(prefix.as_bytes()));
+        self.read_entries(entries)
     }
 
-    // True if number of records in the log exceeds the threshold
-    fn exceeds(&self) -> bool {
-        self.size > self.threshold
+    /// Gets the value of a key, `None` if it isn't set.
+    ///
+    /// Unlike `KvsEngine::get` the key is borrowed, any string type will do. A value
+    /// that isn't valid UTF-8 fails with `KvsError::Utf8Error`.
+    ///
+    /// The read goes through the file handles this handle keeps open, hence `&mut
+    /// self`: a shared borrow would have to open them again on every read, as
+    /// `get_many` does once per call. Concurrent readers each read through a clone of
+    /// the store instead, which never blocks the others.
+    pub fn get(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        match self.get_bytes(key.as_ref().as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Commands {
-    Set(String, String),
-    Rm(String),
-    Get(String),
-}
+    /// Gets the value of a key of arbitrary bytes, `None` if it isn't set.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let value = self.fetch(key)?;
+        #[cfg(feature = "metrics")]
+        self.metrics.get.record(started);
+        Ok(value)
+    }
 
-impl<'a> KvStore<'a> {
-    /// Creates a `KvStore`.
-    pub fn new(p: &'a Path) -> Self {
-        KvStore {
-            map: HashMap::new(),
-            wal: WAL::new(p, "log.txt"),
-            final_offset: 0,
+    fn fetch(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = self.clock.now();
+        let expired = self
+            .map
+            .get(key)
+            .is_some_and(|entry| entry.value().load().expired(now));
+        if expired {
+            self.expire(key, now)?;
+            log::debug!("get {} expired", String::from_utf8_lossy(key));
+            return Ok(None);
         }
+        let value = Self::lookup(&self.map, &mut self.reader, key, now)?;
+        log::debug!(
+            "get {} {}{}",
+            String::from_utf8_lossy(key),
+            if value.is_some() { "hit" } else { "miss" },
+            logged(self.redact_values, value.as_deref())
+        );
+        Ok(value)
     }
 
-    /// Sets the value of a string key to a string.
+    /// Returns a handle on the stored value of a key without reading it, `None` if the
+    /// key isn't set.
     ///
-    /// If the key already exists, the previous value will be overwritten.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        //! this may be an extra clone
-        let v = serde_json::to_string(&Commands::Set(key.clone(), value.clone()))?;
-        let num_bytes = self.wal.append(v)?;
-        // after command is persisted, we update the in-mem index
-        self.map
-            .insert(key, (self.final_offset, self.final_offset + num_bytes));
-        self.final_offset += num_bytes;
-        if self.wal.exceeds() {
-            self.compact()?;
+    /// Only the in-memory index is consulted, so this never fails. Pass the handle to
+    /// `read_handle` to read the value from the log.
+    pub fn try_get_cached(&self, key: &str) -> Option<ValueHandle> {
+        let now = self.clock.now();
+        let position = self.map.get(key.as_bytes())?.value().load();
+        if position.expired(now) {
+            return None;
         }
-        Ok(())
+        Some(ValueHandle {
+            segment: position.segment,
+            offset: position.start as u64,
+            len: position.len,
+            value_len: position.value_len,
+            expires: position.expires,
+        })
     }
 
-    /// Gets the string value of a given string key.
+    /// Reads the value a handle from `try_get_cached` points at.
     ///
-    /// Returns `None` if the given key does not exist.
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(offsets) = self.map.get(&key).cloned() {
-            match self.wal.read_one(offsets)? {
-                Commands::Set(_, v) => return Ok(Some(v)),
-                Commands::Rm(_) => return Ok(None),
-                Commands::Get(_) => return Ok(None),
+    /// That is the value of the key when the handle was taken, even if it was set
+    /// again since, unless a compaction has moved the record. The current value is
+    /// read then, `None` if the key has been removed.
+    pub fn read_handle(&self, key: &str, handle: ValueHandle) -> Result<Option<String>> {
+        let mut reader = self.reader.clone();
+        let position = Position {
+            segment: handle.segment,
+            start: handle.offset as usize,
+            len: handle.len,
+            value_len: handle.value_len,
+            expires: handle.expires,
+        };
+        match reader.read_one(key.as_bytes(), position) {
+            Ok(command) => match command.into_parts() {
+                (_, Some((value, _))) => Ok(Some(String::from_utf8(value)?)),
+                (_, None) => Ok(None),
+            },
+            Err(_) if reader.retired(position) => {
+                Self::lookup_str(&self.map, &mut reader, key, self.clock.now())
             }
+            Err(e) => Err(e),
         }
+    }
 
-        Ok(None)
+    /// Returns the values of several keys at once, in the order they were asked for.
+    ///
+    /// The records are read in log order through a single set of read handles
+    /// rather than seeking back and forth once per key.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut reader = self.reader.clone();
+        let now = self.clock.now();
+        let mut wanted: Vec<(usize, Position)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| {
+                let entry = self.map.get(key.as_bytes())?;
+                Some((i, entry.value().load()))
+            })
+            .filter(|(_, position)| !position.expired(now))
+            .collect();
+        wanted.sort_unstable_by_key(|(_, position)| (position.segment, position.start));
+
+        let mut values = vec![None; keys.len()];
+        for (i, position) in wanted {
+            values[i] = match reader.read_one(keys[i].as_bytes(), position) {
+                Ok(command) => match command.into_parts() {
+                    (_, Some((v, _))) => Some(String::from_utf8(v)?),
+                    (_, None) => None,
+                },
+                // compacted away since, look the key up again
+                Err(_) if reader.retired(position) => {
+                    Self::lookup_str(&self.map, &mut reader, &keys[i], now)?
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(values)
+    }
+
+    // Read the values of index entries as the iterator advances, skipping keys that
+    // were removed or expired, or aren't UTF-8
+    fn read_entries<'a>(
+        &'a self,
+        entries: impl Iterator<Item = Entry<'a, Vec<u8>, AtomicCell<Position>>> + 'a,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a {
+        let mut reader = self.reader.clone();
+        let now = self.clock.now();
+        entries.filter_map(move |entry| {
+            let key = std::str::from_utf8(entry.key()).ok()?;
+            match Self::lookup_str(&self.map, &mut reader, key, now) {
+                Ok(value) => value.map(|v| Ok((key.to_owned(), v))),
+                Err(e) => Some(Err(e)),
+            }
+        })
     }
 
-    /// Remove a given key.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if !self.map.contains_key(&key) {
+    /// Returns a reader over the value of a key, or `None` if it isn't set.
+    ///
+    /// With an uncompressed bincode log the value is streamed from the log rather
+    /// than read into memory, a record that doesn't match its checksum fails the read
+    /// reaching the end of the value. Other logs decode the value into memory first.
+    pub fn get_reader(&self, key: &str) -> Result<Option<impl Read>> {
+        let mut reader = self.reader.clone();
+        let now = self.clock.now();
+        loop {
+            let position = match self.map.get(key.as_bytes()) {
+                Some(entry) => entry.value().load(),
+                None => return Ok(None),
+            };
+            if position.expired(now) {
+                return Ok(None);
+            }
+            match reader.value_reader(key.as_bytes(), position) {
+                // compacted away since, look the key up again
+                Err(_) if reader.retired(position) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Replays every record of the log in the order it was written, removals included.
+    ///
+    /// Records made redundant by a compaction are no longer part of the log, so the
+    /// history only goes back to the last compaction. A record that can't be read is
+    /// yielded as an error and ends the replay.
+    pub fn log_records(&self) -> Result<impl Iterator<Item = Result<LogRecord>>> {
+        self.wal.lock().unwrap().records()
+    }
+
+    /// Writes every record of the log in the directory to `out` without opening the
+    /// store, one per line prefixed by the segment and byte offset it was read from,
+    /// such as `1:5 SET key value`.
+    ///
+    /// A record that can't be read ends the dump with a line describing the error,
+    /// which is then returned.
+    pub fn dump(path: impl AsRef<Path>, mut out: impl Write) -> Result<()> {
+        let mut records = Records::open(&Segments::new(path.as_ref(), None, None, Storage::Disk))?;
+        while let Some((segment, offset, command)) = records.next_command() {
+            match command.map(Commands::into_record) {
+                Ok(record) => writeln!(out, "{}:{} {}", segment, offset, record)?,
+                Err(e) => {
+                    writeln!(out, "{}:{} error: {}", segment, offset, e)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read the value of a key from the log, if it is live and hasn't expired by `now`
+    fn lookup(
+        index: &Positions,
+        reader: &mut LogReader,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(Self::lookup_value(index, reader, key, now)?.map(|(v, _)| v))
+    }
+
+    // Read the value of a key from the log along with its expiry, see `lookup`
+    fn lookup_value(
+        index: &Positions,
+        reader: &mut LogReader,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Value>> {
+        loop {
+            let position = match index.get(key) {
+                Some(entry) => entry.value().load(),
+                None => return Ok(None),
+            };
+            if position.expired(now) {
+                return Ok(None);
+            }
+            match reader.read_one(key, position) {
+                Ok(command) => return Ok(command.into_parts().1),
+                // compaction deleted the segment after the position was looked up,
+                // by then the index already points into the compacted one
+                Err(_) if reader.retired(position) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Read the value of a key as a string, see `lookup`
+    fn lookup_str(
+        index: &Positions,
+        reader: &mut LogReader,
+        key: &str,
+        now: u64,
+    ) -> Result<Option<String>> {
+        match Self::lookup(index, reader, key.as_bytes(), now)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a key, returning the value it held before, if any.
+    pub fn insert(&mut self, key: String, value: String) -> Result<Option<String>> {
+        // the writer lock keeps the old value from changing before the new one lands
+        let mut wal = self.wal.lock().unwrap();
+        let old = Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())?;
+        Self::write_set(&mut wal, &self.map, key.into_bytes(), value.into_bytes())?;
+        Ok(old)
+    }
+
+    /// Sets the key to `new` only if its current value is `expected`, `None` meaning
+    /// the key must be absent. Returns whether the value was swapped.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        // the writer lock keeps the value from changing between the check and the write
+        let mut wal = self.wal.lock().unwrap();
+        let current = Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())?;
+        if current != expected {
+            return Ok(false);
+        }
+        Self::write_set(&mut wal, &self.map, key.into_bytes(), new.into_bytes())?;
+        Ok(true)
+    }
+
+    /// Returns the value of a key, first setting it to the result of `f` if it is absent.
+    ///
+    /// `f` only runs on a miss, while other writes wait for it.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: String,
+        f: impl FnOnce() -> String,
+    ) -> Result<String> {
+        // the writer lock keeps another writer from filling the key in between
+        let mut wal = self.wal.lock().unwrap();
+        if let Some(value) = Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())?
+        {
+            return Ok(value);
+        }
+        let value = f();
+        Self::write_set(
+            &mut wal,
+            &self.map,
+            key.into_bytes(),
+            value.clone().into_bytes(),
+        )?;
+        Ok(value)
+    }
+
+    /// Returns the entry of a key, to read its value and write it back in place.
+    pub fn entry(&mut self, key: String) -> entry::Entry<'_> {
+        entry::Entry::new(self, key)
+    }
+
+    // Apply `modify` to the current value of the key and write it back, or set the
+    // key to the result of `default` if it is absent. A present key without a
+    // change to make is left as it is.
+    pub(crate) fn resolve(
+        &mut self,
+        key: String,
+        modify: Option<Modify<'_>>,
+        default: impl FnOnce() -> String,
+    ) -> Result<String> {
+        // the writer lock keeps the value from changing before the new one lands
+        let mut wal = self.wal.lock().unwrap();
+        let value = match Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())? {
+            Some(mut value) => match modify {
+                Some(modify) => {
+                    modify(&mut value);
+                    value
+                }
+                None => return Ok(value),
+            },
+            None => default(),
+        };
+        Self::write_set(
+            &mut wal,
+            &self.map,
+            key.into_bytes(),
+            value.clone().into_bytes(),
+        )?;
+        Ok(value)
+    }
+
+    /// Adds `by` to the integer value of a key, a missing key counting as 0, and
+    /// returns the new total.
+    ///
+    /// The read and the write happen under the writer lock, so concurrent increments
+    /// through other handles are not lost.
+    pub fn increment(&mut self, key: String, by: i64) -> Result<i64> {
+        let mut wal = self.wal.lock().unwrap();
+        let current = match Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())? {
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| KvsError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+        let total = current
+            .checked_add(by)
+            .ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        Self::write_set(
+            &mut wal,
+            &self.map,
+            key.into_bytes(),
+            total.to_string().into_bytes(),
+        )?;
+        Ok(total)
+    }
+
+    /// Subtracts `by` from the integer value of a key, see `increment`.
+    pub fn decrement(&mut self, key: String, by: i64) -> Result<i64> {
+        let by = by
+            .checked_neg()
+            .ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        self.increment(key, by)
+    }
+
+    /// Appends `suffix` to the value of a key, after `sep`, setting the key to
+    /// `suffix` alone if it is absent.
+    ///
+    /// The read and the write happen under the writer lock as with `increment`, so
+    /// appends through other handles are not lost.
+    pub fn append_value(&mut self, key: String, suffix: &str, sep: &str) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let value = match Self::lookup_str(&self.map, &mut self.reader, &key, self.clock.now())? {
+            Some(mut value) => {
+                value.push_str(sep);
+                value.push_str(suffix);
+                value
+            }
+            None => suffix.to_owned(),
+        };
+        Self::write_set(&mut wal, &self.map, key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a key, failing with `KvsError::KeyNotFound` if it isn't set.
+    ///
+    /// Unlike `KvsEngine::remove` the key is borrowed, any string type will do.
+    pub fn remove(&mut self, key: impl AsRef<str>) -> Result<()> {
+        self.remove_bytes(key.as_ref().as_bytes())
+    }
+
+    /// Removes a key of arbitrary bytes, failing with `KvsError::KeyNotFound` if it
+    /// isn't set.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        if !self.remove_key(key)? {
             return Err(KvsError::KeyNotFound);
         }
-        let v = serde_json::to_string(&Commands::Rm(key.clone()))?;
-        let _ = self.wal.append(v);
-        self.map.remove(&key);
-        if self.wal.exceeds() {
-            self.compact()?;
+        #[cfg(feature = "metrics")]
+        self.metrics.remove.record(started);
+        Ok(())
+    }
+
+    /// Removes a key if it is present, returning whether it was.
+    ///
+    /// Unlike `remove`, an absent key is not an error.
+    pub fn remove_if_present(&mut self, key: impl AsRef<str>) -> Result<bool> {
+        self.remove_key(key.as_ref().as_bytes())
+    }
+
+    fn remove_key(&mut self, key: &[u8]) -> Result<bool> {
+        // an expired key is tombstoned as a read would, but wasn't there to remove
+        let now = self.clock.now();
+        let expired = self
+            .map
+            .get(key)
+            .is_some_and(|entry| entry.value().load().expired(now));
+        if expired {
+            self.expire(key, now)?;
+            return Ok(false);
+        }
+        let mut wal = self.wal.lock().unwrap();
+        if !self.map.contains_key(key) {
+            return Ok(false);
+        }
+        wal.append(&Commands::rm(key.to_vec()))?;
+        wal.flush()?;
+        log::debug!("removed {}", String::from_utf8_lossy(key));
+        // only once the tombstone is persisted, we update the in-mem index
+        Self::unindex(&mut wal, &self.map, key);
+        wal.subscribers
+            .notify(|| ChangeEvent::Remove { key: key.to_vec() });
+        Self::maintain(&mut wal, &self.map)?;
+        Ok(true)
+    }
+
+    /// Sets the value of a key, overwriting any previous value.
+    ///
+    /// Owned strings move into the log and the index without being copied, a
+    /// borrowed key or value is copied once.
+    pub fn set<'k, 'v>(
+        &mut self,
+        key: impl Into<Cow<'k, str>>,
+        value: impl Into<Cow<'v, str>>,
+    ) -> Result<()> {
+        let (key, value) = (key.into().into_owned(), value.into().into_owned());
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets a key of arbitrary bytes to a value of arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten. The bytes
+    /// are stored as they are, in a JSON log as an array of numbers unless both are
+    /// valid UTF-8.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let mut wal = self.wal.lock().unwrap();
+        Self::write_set(&mut wal, &self.map, key, value)?;
+        #[cfg(feature = "metrics")]
+        self.metrics.set.record(started);
+        Ok(())
+    }
+
+    /// Sets the value of a key that expires once the time to live has passed.
+    ///
+    /// The time to live is counted in whole seconds against the configured clock.
+    /// Setting the key again without a time to live makes it permanent.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires = self.clock.now().saturating_add(ttl.as_secs());
+        let mut wal = self.wal.lock().unwrap();
+        Self::write(
+            &mut wal,
+            &self.map,
+            Commands::SetWithTtl(key, value, expires),
+        )
+    }
+
+    // Append a tombstone for a key that has expired, unless it was set again since
+    fn expire(&mut self, key: &[u8], now: u64) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        // the key reads as missing all the same
+        if wal.read_only {
+            return Ok(());
+        }
+        if !self
+            .map
+            .get(key)
+            .is_some_and(|entry| entry.value().load().expired(now))
+        {
+            return Ok(());
         }
+        wal.append(&Commands::rm(key.to_vec()))?;
+        wal.flush()?;
+        Self::unindex(&mut wal, &self.map, key);
+        wal.subscribers
+            .notify(|| ChangeEvent::Remove { key: key.to_vec() });
         Ok(())
     }
 
-    /// Compact the log file when it exceeds a certain size threshold
-    fn compact(&mut self) -> Result<()> {
-        // take a stream of Commands from the wal, into a map
-        // also keep an ordered vec of keys to rebuild the log.
-        let mut mapping: HashMap<String, String> = HashMap::new();
-        let commands = self.wal.stream()?;
-        for c in commands {
-            match c {
-                Commands::Set(k, v) => {
-                    mapping.insert(k, v);
-                    ()
+    /// Sets the value of every key in the batch, flushing the log once at the end.
+    ///
+    /// A key appearing more than once in the batch ends up with its last value.
+    pub fn set_many(&mut self, entries: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        // a batch past the size limits is refused as a whole
+        let entries: Vec<_> = entries.into_iter().collect();
+        for (key, value) in &entries {
+            wal.check_size(key.as_bytes(), value.len())?;
+        }
+        let mut positions = Vec::new();
+        for (key, value) in entries {
+            let command = Commands::Set(key, value);
+            let position = wal.append(&command)?;
+            positions.push((command.into_parts().0, position));
+        }
+        wal.flush()?;
+        log::debug!("set {} keys in a batch", positions.len());
+        // after the batch is persisted, we update the in-mem index
+        for (key, position) in positions {
+            wal.subscribers
+                .notify(|| ChangeEvent::Set { key: key.clone() });
+            Self::index(&mut wal, &self.map, key, position);
+        }
+        Self::maintain(&mut wal, &self.map)?;
+        Ok(())
+    }
+
+    /// Starts a batch of writes that are applied together by `TxnBatch::commit`.
+    pub fn transaction(&mut self) -> TxnBatch<'_> {
+        TxnBatch::new(self)
+    }
+
+    // Append the commands of a transaction as one group and apply them to the index
+    pub(crate) fn commit(&mut self, commands: Vec<Commands>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let mut wal = self.wal.lock().unwrap();
+        wal.writable()?;
+        // a removal of a missing key fails the whole transaction
+        let now = self.clock.now();
+        let mut written: HashMap<&[u8], bool> = HashMap::new();
+        for command in &commands {
+            let key = command.key();
+            let live = written.get(key).copied().unwrap_or_else(|| {
+                self.map
+                    .get(key)
+                    .is_some_and(|entry| !entry.value().load().expired(now))
+            });
+            if command.value().is_none() && !live {
+                return Err(KvsError::KeyNotFound);
+            }
+            written.insert(key, command.value().is_some());
+        }
+        let positions = wal.append_group(&commands)?;
+        wal.flush()?;
+        log::debug!("committed a transaction of {} writes", commands.len());
+        // the index only changes once the whole group is persisted
+        for (command, position) in commands.into_iter().zip(positions) {
+            match command.into_parts() {
+                (key, Some(_)) => {
+                    wal.subscribers
+                        .notify(|| ChangeEvent::Set { key: key.clone() });
+                    Self::index(&mut wal, &self.map, key, position);
                 }
-                Commands::Rm(k) => {
-                    mapping.remove(&k);
-                    ()
+                (key, None) => {
+                    Self::unindex(&mut wal, &self.map, &key);
+                    wal.subscribers
+                        .notify(|| ChangeEvent::Remove { key: key.clone() });
                 }
-                Commands::Get(_) => (),
             }
         }
-        // if error occurs here, could be bad
-        self.wal.clear()?;
+        Self::maintain(&mut wal, &self.map)?;
+        Ok(())
+    }
 
-        for (k, v) in mapping.iter() {
-            let v = serde_json::to_string(&Commands::Set(k.clone(), v.clone()))?;
-            let _ = self.wal.append(v)?;
+    /// Copies every live key of `other` into this store, overwriting the keys the two
+    /// have in common. Keys with a time to live keep their expiry.
+    ///
+    /// The values are read one at a time and flushed to the log in batches, so
+    /// neither store is loaded into memory. Other writes to this store wait for the
+    /// merge to finish, writes to `other` in the meantime may or may not be copied.
+    pub fn merge(&mut self, other: &KvStore) -> Result<()> {
+        let mut reader = other.reader.clone();
+        let now = other.clock.now();
+        let mut wal = self.wal.lock().unwrap();
+        let mut positions = Vec::with_capacity(MERGE_BATCH);
+        let mut merged = 0;
+        for entry in other.map.iter() {
+            let key = entry.key();
+            if let Some((value, expires)) = Self::lookup_value(&other.map, &mut reader, key, now)? {
+                let command = Commands::set(key.clone(), value, expires);
+                let position = match wal.append(&command) {
+                    Ok(position) => position,
+                    Err(e) => {
+                        // what was merged so far stays merged
+                        Self::index_batch(&mut wal, &self.map, &mut positions)?;
+                        return Err(e);
+                    }
+                };
+                positions.push((command.into_parts().0, position));
+                merged += 1;
+            }
+            if positions.len() == MERGE_BATCH {
+                Self::index_batch(&mut wal, &self.map, &mut positions)?;
+            }
         }
+        Self::index_batch(&mut wal, &self.map, &mut positions)?;
+        log::debug!("merged {} keys", merged);
+        Self::maintain(&mut wal, &self.map)?;
+        Ok(())
+    }
 
+    // Flush the appended records and point the index at them
+    fn index_batch(
+        wal: &mut Wal,
+        index: &Positions,
+        positions: &mut Vec<(Vec<u8>, Position)>,
+    ) -> Result<()> {
+        wal.flush()?;
+        for (key, position) in positions.drain(..) {
+            wal.subscribers
+                .notify(|| ChangeEvent::Set { key: key.clone() });
+            Self::index(wal, index, key, position);
+        }
         Ok(())
-        // then overwrite the log, maybe using a temp + swap, or
-        // or just straight up overwrite for now
     }
 
-    /// Initializes the in-mem index by regenerating from the existing log
-    fn intialize_index(&mut self, path: &Path) -> Result<()> {
-        let f = File::open(path)?;
-        let mut map: HashMap<String, (usize, usize)> = HashMap::new();
+    /// Writes a compacted copy of the store, as it was when the snapshot started, to
+    /// the directory `dest`, which must be empty or missing.
+    ///
+    /// Writes only wait for the start of the snapshot to be taken, the live records
+    /// are then copied over in log order while reads and writes carry on as usual.
+    /// The copy is synced before this returns, and opens like any other store.
+    pub fn snapshot(&mut self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        if fs::read_dir(dest).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is not empty", dest.display()),
+            )
+            .into());
+        }
+        let (mut reader, mut entries, format, custom) = {
+            let mut wal = self.wal.lock().unwrap();
+            wal.write_out()?;
+            // the handles keep the records readable through a later compaction
+            let reader = self.reader.pinned()?;
+            let entries: Vec<(Vec<u8>, Position)> = self
+                .map
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().load()))
+                .collect();
+            (reader, entries, wal.format, wal.segments.custom.clone())
+        };
+        entries.sort_unstable_by_key(|(_, position)| (position.segment, position.start));
 
-        // Collect all data from logs to generate the in memory index
-        let mut stream = serde_json::Deserializer::from_reader(&f).into_iter::<Commands>();
+        let mut config = KvStoreConfig::new()
+            .format(format)
+            .threshold(None)
+            .clock(self.clock.clone());
+        if let Some(custom) = custom {
+            config = config.codec(custom);
+        }
+        let copy = KvStore::open_with(dest, config)?;
+        let now = self.clock.now();
+        let mut wal = copy.wal.lock().unwrap();
+        for (key, position) in entries {
+            if position.expired(now) {
+                continue;
+            }
+            let (key, value) = reader.read_one(&key, position)?.into_parts();
+            if let Some((value, expires)) = value {
+                wal.append(&Commands::set(key, value, expires))?;
+            }
+        }
+        wal.sync()?;
+        log::info!("snapshot written to {}", dest.display());
+        Ok(())
+    }
 
-        let mut current_offset: usize = 0;
+    /// Writes every live key/value pair to `w` as a single JSON object, sorted by key.
+    pub fn export(&self, w: impl Write) -> Result<()> {
+        let pairs = self.iter().collect::<Result<BTreeMap<String, String>>>()?;
+        serde_json::to_writer(w, &pairs)?;
+        Ok(())
+    }
+
+    /// Sets every pair of a JSON object read from `r`, as written by `export`.
+    ///
+    /// Keys that already exist are overwritten, the others are left as they are.
+    pub fn import(&mut self, r: impl Read) -> Result<()> {
+        let pairs: HashMap<String, String> = serde_json::from_reader(r)?;
+        self.set_many(pairs)
+    }
+
+    // Point the key at a new position, the record at the old one is dead from now on
+    fn index(wal: &mut Wal, index: &Positions, key: Vec<u8>, position: Position) {
+        wal.live += position.len as u64;
+        match index.get(&key) {
+            Some(entry) => {
+                let old = entry.value().swap(position);
+                wal.live = wal.live.saturating_sub(old.len as u64);
+            }
+            None => {
+                index.insert(key, AtomicCell::new(position));
+            }
+        }
+    }
+
+    // Drop the key from the index, its record is dead from now on
+    fn unindex(wal: &mut Wal, index: &Positions, key: &[u8]) {
+        if let Some(entry) = index.remove(key) {
+            let old = entry.value().load();
+            wal.live = wal.live.saturating_sub(old.len as u64);
+        }
+    }
+
+    // Append a `Set` and point the index at it, the writer lock must be held
+    fn write_set(wal: &mut Wal, index: &Positions, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        Self::write(wal, index, Commands::set(key, value, None))
+    }
+
+    // Append a command setting a key and point the index at it. The command is
+    // encoded from a borrow, its key then moves into the index without a copy.
+    fn write(wal: &mut Wal, index: &Positions, command: Commands) -> Result<()> {
+        let position = wal.append(&command)?;
+        let (key, value) = command.into_parts();
+        let value = value.as_ref().map(|(value, _)| value.as_slice());
+        Self::written(wal, index, key, position, value)
+    }
+
+    // Point the index at an appended record setting the key once it is persisted,
+    // the value is only passed along to be logged
+    fn written(
+        wal: &mut Wal,
+        index: &Positions,
+        key: Vec<u8>,
+        position: Position,
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        wal.flush()?;
+        log::debug!(
+            "set {} ({} bytes at {}:{}){}",
+            String::from_utf8_lossy(&key),
+            position.len,
+            position.segment,
+            position.start,
+            logged(wal.redact_values, value)
+        );
+        // after command is persisted, we update the in-mem index
+        wal.subscribers
+            .notify(|| ChangeEvent::Set { key: key.clone() });
+        Self::index(wal, index, key, position);
+        Self::maintain(wal, index)?;
+        Ok(())
+    }
+
+    /// Sets the value of a key to everything read from `value`.
+    ///
+    /// With a bincode log the value is copied into the log as it is read instead of
+    /// being collected into a `String` first, with a JSON log or one written through a
+    /// `RecordCodec` it is read into memory.
+    /// Other writes wait for the copy to finish. The value must be valid UTF-8 and
+    /// smaller than 4 GiB, on failure the key keeps its previous value.
+    pub fn set_from_reader(&mut self, key: String, mut value: impl Read) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        if !wal.active_codec().streams_values() {
+            let mut buf = String::new();
+            value.read_to_string(&mut buf)?;
+            return Self::write_set(&mut wal, &self.map, key.into_bytes(), buf.into_bytes());
+        }
+        let position = wal.append_from(&key, &mut value)?;
+        Self::written(&mut wal, &self.map, key.into_bytes(), position, None)
+    }
+
+    /// Subscribes to changes of the store, returning a receiver of an event for every
+    /// key that is set or removed through any handle.
+    ///
+    /// An event is sent once its write succeeded, in the order the writes happened.
+    /// A key that expires is reported as removed once a read notices it has. Events
+    /// are queued for each subscriber separately, up to 1024 of them, a subscriber
+    /// that falls further behind misses further events until it catches up, with a
+    /// warning logged for each one dropped. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        self.wal.lock().unwrap().subscribers.subscribe()
+    }
+
+    /// Returns counters describing the store and its log.
+    pub fn stats(&self) -> KvStats {
+        // the index doesn't change while the writer is held
+        let wal = self.wal.lock().unwrap();
+        let now = self.clock.now();
+        let (mut live_keys, mut live_bytes) = (0, 0);
+        for entry in self.map.iter() {
+            let position = entry.value().load();
+            if !position.expired(now) {
+                live_keys += 1;
+                live_bytes += position.len as u64;
+            }
+        }
+        KvStats {
+            live_keys,
+            records: wal.records,
+            dead_bytes: wal.size.saturating_sub(live_bytes),
+            log_size: wal.size,
+            compactions: wal.compactions,
+            needs_compaction: wal.exceeds(),
+            #[cfg(feature = "metrics")]
+            timings: self.metrics.timings(),
+        }
+    }
+
+    /// Returns the size in bytes the log takes up on disk, its segments and their
+    /// hints together, from the lengths of the files rather than a replay.
+    ///
+    /// Buffered records are written out first so that every write that returned
+    /// counts. A store kept in memory reports the size its files would have.
+    pub fn disk_size(&self) -> Result<u64> {
+        let mut wal = self.wal.lock().unwrap();
+        if !wal.read_only {
+            wal.write_out()?;
+        }
+        let segments = &wal.segments;
+        let mut files: Vec<PathBuf> = segments.legacy().into_iter().collect();
+        for id in segments.ids()? {
+            files.push(segments.path(id));
+            files.push(segments.hint(id));
+        }
         let mut size = 0;
-        let mut processing = true;
-        while processing {
-            if let Some(command) = stream.next() {
-                let offset = stream.byte_offset();
-
-                match command? {
-                    Commands::Set(k, _) => {
-                        map.insert(k, (current_offset, offset));
-                        ()
-                    }
-                    Commands::Rm(k) => {
-                        map.remove(&k);
-                        ()
-                    }
-                    Commands::Get(_) => (),
+        for file in files {
+            match segments.storage.len(&file) {
+                Ok(len) => size += len,
+                // no hint for a segment that wasn't compacted, or removed meanwhile
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(size)
+    }
+
+    /// Replays the log and compares what it holds with the index in memory.
+    ///
+    /// Every segment is replayed record by record, hints are not trusted, so this
+    /// reads the whole log. Writes wait for the replay to finish. A record that can't
+    /// be read fails the check with the error of the replay.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut wal = self.wal.lock().unwrap();
+        if !wal.read_only {
+            wal.write_out()?;
+        }
+        let mut replayed: Index = HashMap::new();
+        for id in wal.segments.ids()? {
+            let codec = wal.segments.codec(id)?;
+            // nothing is truncated, a partial append at the end is left to the writer
+            let tail = match id == wal.active {
+                true => Tail::Keep,
+                false => Tail::Strict,
+            };
+            let path = wal.segments.path(id);
+            wal::index_segment(&wal.segments.storage, &path, id, codec, &mut replayed, tail)?;
+        }
+        let indexed = self
+            .map
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()));
+        Ok(verify::compare(indexed, replayed, self.clock.now()))
+    }
+
+    /// Returns the number of live keys found by replaying the log, see `verify`.
+    pub fn keys_count_on_disk(&self) -> Result<u64> {
+        Ok(self.verify()?.on_disk)
+    }
+
+    /// Writes out any buffered records and syncs the log to the storage device.
+    ///
+    /// Unless the store was opened with `SyncMode::Always`, a write that returned `Ok`
+    /// can still be lost to a power loss or OS crash until this is called. Dropping a
+    /// handle writes out buffered records too, but doesn't sync them.
+    pub fn flush(&mut self) -> Result<()> {
+        self.wal.lock().unwrap().sync()
+    }
+
+    /// Returns true if the log has outgrown the compaction threshold.
+    ///
+    /// With `CompactionMode::Deferred` this is the hint to call `compact`.
+    pub fn should_compact(&self) -> bool {
+        self.wal.lock().unwrap().exceeds()
+    }
+
+    /// Compact the log, dropping overwritten and removed records.
+    ///
+    /// The live records of every segment are written to a fresh segment and the old
+    /// segments are deleted. Records are copied over one at a time, so compacting
+    /// doesn't hold the live values in memory. Unless compaction is deferred this
+    /// runs automatically when the log exceeds the compaction threshold, but is safe
+    /// to call at any time. Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64> {
+        let mut wal = self.wal.lock().unwrap();
+        Self::compact_log(&mut wal, &self.map)
+    }
+
+    /// Compacts part of the log, scanning about `budget` bytes of its records.
+    ///
+    /// The first call starts a pass over the log, later ones carry on where the
+    /// previous one left off. The live records of the oldest segment are appended to
+    /// the log again, and the segment is deleted once they all are. Reads and writes
+    /// go on as normal between steps, and a write that takes the log past the
+    /// threshold doesn't start another pass while one is in progress. Only the
+    /// segments written before the pass started are compacted. `compact` finishes a
+    /// pass in one go. Returns true once the pass is complete.
+    pub fn compact_step(&mut self, budget: u64) -> Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        Self::compact_chunk(&mut wal, &self.map, budget)
+    }
+
+    // Compact the log after a write as the compaction mode says to
+    fn maintain(wal: &mut Wal, index: &Positions) -> Result<()> {
+        if wal.compact_inline() {
+            Self::compact_log(wal, index)?;
+        } else if let Some(budget) = wal.step_due() {
+            Self::compact_chunk(wal, index, budget)?;
+        }
+        Ok(())
+    }
+
+    // A step of an incremental compaction. Records are only copied while the index
+    // points at them, and the index points at the copies once they are written out.
+    fn compact_chunk(wal: &mut Wal, index: &Positions, budget: u64) -> Result<bool> {
+        wal.writable()?;
+        let mut cursor = match wal.cursor.take() {
+            Some(cursor) => cursor,
+            None => {
+                // everything written so far is compacted, appends carry on past it
+                let last = wal.active;
+                wal.roll()?;
+                Cursor {
+                    segment: wal.segments.ids()?.first().copied().unwrap_or(last),
+                    offset: HEADER_LEN as u64,
+                    records: 0,
+                    last,
+                }
+            }
+        };
+        let now = wal.clock.now();
+        let mut scanned = 0;
+        while scanned < budget && cursor.segment <= cursor.last {
+            let path = wal.segments.path(cursor.segment);
+            let codec = wal.segments.codec(cursor.segment)?;
+            let (commands, next) = wal::read_chunk(
+                &wal.segments.storage,
+                &path,
+                cursor.segment,
+                &codec,
+                cursor.offset,
+                cursor.records,
+                budget - scanned,
+            )?;
+            let mut copies = Vec::new();
+            for (start, command) in commands {
+                cursor.records += 1;
+                let live = index
+                    .get(command.key())
+                    .map(|entry| entry.value().load())
+                    .filter(|p| p.segment == cursor.segment && p.start as u64 == start);
+                let position = match live {
+                    Some(position) => position,
+                    // overwritten, removed, or a tombstone
+                    None => continue,
+                };
+                if position.expired(now) {
+                    Self::unindex(wal, index, command.key());
+                    continue;
+                }
+                if let (key, Some((value, expires))) = command.into_parts() {
+                    let command = Commands::set(key, value, expires);
+                    let copy = wal.append(&command)?;
+                    copies.push((command.into_parts().0, copy));
+                }
+            }
+            wal.write_out()?;
+            for (key, position) in copies {
+                Self::index(wal, index, key, position);
+            }
+            match next {
+                Some(next) => {
+                    scanned += next - cursor.offset;
+                    cursor.offset = next;
                 }
-                current_offset = offset;
-                size += 1;
-            } else {
-                processing = false;
+                None => {
+                    scanned += wal
+                        .segments
+                        .storage
+                        .len(&path)?
+                        .saturating_sub(cursor.offset);
+                    wal.drop_oldest(cursor.segment, cursor.records)?;
+                    let following = wal
+                        .segments
+                        .ids()?
+                        .into_iter()
+                        .find(|&id| id > cursor.segment);
+                    cursor = Cursor {
+                        segment: following.unwrap_or(cursor.last + 1),
+                        offset: HEADER_LEN as u64,
+                        records: 0,
+                        ..cursor
+                    };
+                }
+            }
+        }
+        if cursor.segment <= cursor.last {
+            wal.cursor = Some(cursor);
+            return Ok(false);
+        }
+        wal.compactions += 1;
+        log::info!("finished incremental compaction, log is {} bytes", wal.size);
+        Ok(true)
+    }
+
+    /// Removes every key, leaving an empty store and a log of empty segments.
+    ///
+    /// A removal of every key is synced to the log before the segments holding the
+    /// old records are replaced, so a crash part way through still reopens empty.
+    pub fn clear(&mut self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        for entry in self.map.iter() {
+            wal.append(&Commands::rm(entry.key().clone()))?;
+        }
+        wal.sync()?;
+        let (compacted, _) = wal.rewrite(HashMap::new())?;
+        for entry in self.map.iter() {
+            wal.subscribers.notify(|| ChangeEvent::Remove {
+                key: entry.key().clone(),
+            });
+        }
+        self.map.clear();
+        wal.live = 0;
+        wal.retire(compacted);
+        Ok(())
+    }
+
+    /// Re-reads the log from scratch, dropping damaged records, and rebuilds the index
+    /// from what is left.
+    ///
+    /// For use when the log on disk is suspected to have drifted from the index, say
+    /// after it was damaged while the store was open. With `RepairMode::Skip` every
+    /// intact record is kept and the log is compacted to get rid of the damage, with
+    /// `RepairMode::Truncate` a damaged segment is cut short at its first damaged
+    /// record. The report says how many records were read and how much was dropped.
+    pub fn repair(&mut self, mode: RepairMode) -> Result<RepairReport> {
+        let mut wal = self.wal.lock().unwrap();
+        wal.writable()?;
+        wal.write_out()?;
+        // the records it counted may be gone
+        wal.cursor = None;
+        let mut report = RepairReport::default();
+        let mut commands = Vec::new();
+        for id in wal.segments.ids()? {
+            let segment = wal.segments.path(id);
+            let codec = wal.segments.codec(id)?;
+            let scan = repair::scan_segment(
+                &wal.segments.storage,
+                &segment,
+                &codec,
+                mode == RepairMode::Skip,
+            )?;
+            report.scanned += scan.report.scanned;
+            report.skipped += scan.report.skipped;
+            report.dropped_bytes += scan.report.dropped_bytes;
+            commands.extend(scan.commands);
+            if let (RepairMode::Truncate, Some(offset)) = (mode, scan.first_damage) {
+                log::warn!(
+                    "truncating log segment {} at damaged record at offset {}",
+                    id,
+                    offset
+                );
+                // the hint lists records that are gone
+                let _ = wal.segments.storage.remove(&wal.segments.hint(id));
+                wal.segments
+                    .storage
+                    .open_write(&segment)?
+                    .set_len(offset as u64)?;
             }
         }
+        if mode == RepairMode::Skip && report.skipped > 0 {
+            log::warn!(
+                "skipped {} damaged stretches of the log, {} bytes",
+                report.skipped,
+                report.dropped_bytes
+            );
+            let (compacted, _) = wal.rewrite(wal::live_values(commands, self.clock.now()))?;
+            wal.retire(compacted);
+        }
+        drop(wal);
+        self.intialize_index(0)?;
+        Ok(report)
+    }
+
+    // Readers carry on throughout, the old segments are only deleted once the
+    // index points into the compacted one
+    fn compact_log(wal: &mut Wal, index: &Positions) -> Result<u64> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let before = wal.size;
+        // the index points at the live record of every key, which are copied over
+        // one at a time
+        let now = wal.clock.now();
+        let live = index
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+            .filter(|(_, position)| !position.expired(now))
+            .collect();
+        let (compacted, fresh) = wal.rewrite_positions(live)?;
+        // keys that expired are in the old index only
+        for entry in index.iter() {
+            if !fresh.contains_key(entry.key()) {
+                Self::unindex(wal, index, entry.key());
+            }
+        }
+        for (key, position) in fresh {
+            Self::index(wal, index, key, position);
+        }
+        wal.retire(compacted);
+        wal.compactions += 1;
 
-        self.final_offset = current_offset;
-        self.wal.size = size;
-        self.map = map;
+        let reclaimed = before.saturating_sub(wal.size);
+        log::info!(
+            "compacted log from {} to {} bytes, reclaimed {} bytes",
+            before,
+            wal.size,
+            reclaimed
+        );
+        #[cfg(feature = "metrics")]
+        wal.metrics.compact.record(started);
+        Ok(reclaimed)
+    }
+
+    // Rewrite the legacy log and any segments of another format or without a header
+    // into a single segment in the configured format
+    fn migrate(&mut self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let legacy = wal.segments.legacy();
+        let mut commands = Vec::new();
+        let storage = wal.segments.storage.clone();
+        if let Some(legacy) = &legacy {
+            commands = wal::read_commands(&storage, legacy, 0, wal.segments.detect(legacy)?)?;
+        }
+        for id in wal.segments.ids()? {
+            let segment = wal.segments.path(id);
+            let layout = wal.segments.detect(&segment)?;
+            commands.extend(wal::read_commands(&storage, &segment, id, layout)?);
+        }
+        let (compacted, _) = wal.rewrite(wal::live_values(commands, self.clock.now()))?;
+        wal.retire(compacted);
+        if let Some(legacy) = legacy.filter(|legacy| storage.exists(legacy)) {
+            storage.remove(&legacy)?;
+        }
         Ok(())
     }
 
-    /// Open and intialize in-mem index from provided log file
-    pub fn open(path: &Path) -> Result<KvStore> {
-        let file_name = "log.txt";
-        let f = path.join(file_name);
-        if !f.exists() {
-            File::create(&f)?;
+    // True if the legacy log exists or a segment isn't framed in the configured format
+    fn needs_migration(&self) -> Result<bool> {
+        let wal = self.wal.lock().unwrap();
+        if wal
+            .segments
+            .legacy()
+            .is_some_and(|legacy| wal.segments.storage.exists(&legacy))
+        {
+            return Ok(true);
+        }
+        for id in wal.segments.ids()? {
+            match wal.segments.detect(&wal.segments.path(id))? {
+                // a read-only log is read in whatever format it was written in
+                Layout::Framed(existing)
+                    if wal.read_only || existing.same_records(&wal.active_codec()) => {}
+                _ => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Initializes the in-mem index by regenerating from the existing segments
+    ///
+    /// The keys are collected with room for at least `capacity` of them.
+    fn intialize_index(&mut self, capacity: usize) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let estimate = wal::estimate_records(&wal.segments)?;
+        let mut map: Index = HashMap::with_capacity(capacity.max(estimate));
+
+        // Collect all data from the segments, oldest first, to generate the in memory index.
+        // Only the active segment can end in a partial append.
+        let (mut size, mut records) = (0, 0);
+        for id in wal.segments.ids()? {
+            let segment = wal.segments.path(id);
+            let active = id == wal.active;
+            // a compacted segment lists its records in a hint, nothing to replay
+            if let Some((len, hinted)) = wal::read_hint(&wal.segments, id)? {
+                if active {
+                    wal.active_len = len;
+                }
+                size += len;
+                records += hinted.len() as u64;
+                map.extend(hinted);
+                continue;
+            }
+            let codec = wal.segments.codec(id)?;
+            let tail = match (active, wal.read_only) {
+                (false, _) => Tail::Strict,
+                (true, false) => Tail::Truncate,
+                (true, true) => Tail::Keep,
+            };
+            let (len, count) =
+                wal::index_segment(&wal.segments.storage, &segment, id, codec, &mut map, tail)?;
+            if active {
+                // the scan ends at the end of the segment, a torn tail was truncated
+                wal.active_len = len;
+            }
+            size += len;
+            records += count;
+        }
+
+        wal.size = size;
+        wal.records = records;
+        self.map.clear();
+        wal.live = 0;
+        for (key, position) in map {
+            Self::index(&mut wal, &self.map, key, position);
         }
+        Ok(())
+    }
+
+    /// Open and intialize in-mem index from the log in the provided directory
+    pub fn open(path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreConfig::default())
+    }
+
+    /// Open the log in the provided directory for reads only, see
+    /// `KvStoreConfig::read_only`
+    ///
+    /// A log that would need to be rewritten before it can be read, such as one
+    /// written by a version without segments, fails to open with `KvsError::ReadOnly`.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreConfig::default().read_only(true))
+    }
+
+    /// Open the log named by a file path rather than a directory, creating it if missing
+    ///
+    /// The log is split into segments, so it is kept next to the path as
+    /// `{file name}.{id}.log` rather than in the file itself, the same as a store
+    /// opened in the parent directory with the file name as `KvStoreConfig::name`.
+    pub fn open_file(path: impl AsRef<Path>) -> Result<KvStore> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        KvStore::open_with(dir, KvStoreConfig::default().name(name))
+    }
 
-        let mut store = KvStore::new(path);
-        store.intialize_index(&f)?;
+    /// Open with the provided options and intialize in-mem index from the log
+    ///
+    /// A log written in another format, or by a version without a log header or
+    /// without segments, is rewritten in the configured format first. A directory
+    /// holding the data of another engine fails with `KvsError::WrongEngine`.
+    pub fn open_with(path: impl AsRef<Path>, config: KvStoreConfig) -> Result<KvStore> {
+        let path = path.as_ref();
+        engine::check_engine(path, "kvs", !config.read_only)?;
+        KvStore::open_on(path, config, Storage::Disk)
+    }
+
+    /// Open an empty store whose log is kept in memory rather than on disk
+    ///
+    /// Nothing touches the filesystem, the log is gone once the last handle is dropped.
+    /// Otherwise the store works the same as one on disk, segments and compaction
+    /// included, which makes it handy for tests.
+    pub fn open_in_memory() -> Result<KvStore> {
+        KvStore::open_in_memory_with(KvStoreConfig::default())
+    }
+
+    /// Open an empty store kept in memory with the provided options, see
+    /// `open_in_memory`
+    pub fn open_in_memory_with(config: KvStoreConfig) -> Result<KvStore> {
+        KvStore::open_on(Path::new(""), config, Storage::memory())
+    }
+
+    fn open_on(path: &Path, config: KvStoreConfig, storage: Storage) -> Result<KvStore> {
+        let capacity = config.capacity;
+        let mut store = KvStore::with_config(path, config, storage)?;
+        if store.needs_migration()? {
+            store.migrate()?;
+        }
+        store.intialize_index(capacity)?;
         Ok(store)
     }
 }
+
+impl Drop for KvStore {
+    // errors can't be reported from here, `flush` first to see them
+    fn drop(&mut self) {
+        if let Ok(mut wal) = self.wal.lock() {
+            let _ = wal.write_out();
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+    /// Iterates over every live key/value pair, see `KvStore::iter`.
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+// Values stay out of it, whatever they hold. Only counts and offsets are shown.
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let wal = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("KvStore")
+            .field("keys", &self.map.len())
+            .field("log_size", &wal.size)
+            .field("records", &wal.records)
+            .field("active_segment", &wal.active)
+            .field("active_len", &wal.active_len)
+            .finish_non_exhaustive()
+    }
+}
+
+// The value of an operation as it is appended to its log line, nothing when values
+// are redacted or there is none
+fn logged(redact: bool, value: Option<&[u8]>) -> String {
+    match value {
+        Some(value) if !redact => format!(": {}", String::from_utf8_lossy(value)),
+        _ => String::new(),
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        KvStore::flush(self)
+    }
+}
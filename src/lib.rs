@@ -1,9 +1,51 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use engine::KvsEngine;
+pub use client::KvsClient;
+pub use clock::{Clock, SystemClock};
+pub use config::{CompactionMode, KvStoreConfig, SyncMode};
+pub use engine::{existing_engine, resolve_engine, KvsEngine};
+pub use entry::Entry;
 pub use error::{KvsError, Result};
+pub use format::{LogFormat, RecordCodec};
+pub use handle::ValueHandle;
 pub use kv::KvStore;
+pub use memory_engine::InMemoryKvsEngine;
+#[cfg(feature = "metrics")]
+pub use metrics::{OpTimings, Timings};
+pub use protocol::{Request, Response};
+pub use repair::{RepairMode, RepairReport};
+pub use server::{KvsServer, ShutdownHandle};
+pub use sharded::ShardedKvStore;
+pub use sled_engine::SledKvsEngine;
+pub use stats::KvStats;
+pub use thread_pool::{SharedQueueThreadPool, ThreadPool};
+pub use txn::TxnBatch;
+pub use verify::VerifyReport;
+pub use wal::LogRecord;
+pub use watch::ChangeEvent;
+mod appender;
+mod client;
+mod clock;
+mod config;
 mod engine;
+mod entry;
 mod error;
+mod format;
+mod handle;
 mod kv;
+mod memory_engine;
+#[cfg(feature = "metrics")]
+mod metrics;
+pub mod protocol;
+mod repair;
+mod server;
+mod sharded;
+mod sled_engine;
+mod stats;
+mod storage;
+mod thread_pool;
+mod txn;
+mod verify;
+mod wal;
+mod watch;
@@ -0,0 +1,53 @@
+use crate::{KvsEngine, KvsError, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The `InMemoryKvsEngine` keeps string key/value pairs in a `HashMap` only.
+///
+/// Nothing is ever written to disk, everything is lost once the last handle is
+/// dropped. Cloning yields another handle to the same pairs. Meant for tests and
+/// ephemeral use.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{InMemoryKvsEngine, KvsEngine, Result};
+/// # fn try_main() -> Result<()>{
+/// let mut store = InMemoryKvsEngine::new();
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryKvsEngine {
+    map: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl InMemoryKvsEngine {
+    /// Creates an empty `InMemoryKvsEngine`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvsEngine for InMemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How often one kind of operation ran and how long it took altogether, see
+/// `Timings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpTimings {
+    /// Number of operations that completed successfully.
+    pub count: u64,
+    /// Time spent in them altogether.
+    pub total: Duration,
+}
+
+impl OpTimings {
+    /// Mean time an operation took, zero if none ran.
+    pub fn average(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total.as_nanos() / count as u128) as u64),
+        }
+    }
+}
+
+/// Time spent in the operations of a `KvStore` since it was opened, as reported in
+/// `KvStats::timings`.
+///
+/// Only built with the `metrics` feature. Every handle of a store adds to the same
+/// timings. A write that compacts the log inline counts the compaction in its own
+/// time as well as in `compact`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timings {
+    /// Calls to `KvStore::set` and `set_bytes`.
+    pub set: OpTimings,
+    /// Calls to `KvStore::get` and `get_bytes`.
+    pub get: OpTimings,
+    /// Calls to `KvStore::remove` and `remove_bytes`.
+    pub remove: OpTimings,
+    /// Compactions of the log, automatic ones included.
+    pub compact: OpTimings,
+}
+
+// Accumulators behind `Timings`, shared by every handle of a store
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) set: Counter,
+    pub(crate) get: Counter,
+    pub(crate) remove: Counter,
+    pub(crate) compact: Counter,
+}
+
+impl Metrics {
+    pub(crate) fn timings(&self) -> Timings {
+        Timings {
+            set: self.set.load(),
+            get: self.get.load(),
+            remove: self.remove.load(),
+            compact: self.compact.load(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counter {
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl Counter {
+    // Count an operation that started at `started` and just completed
+    pub(crate) fn record(&self, started: Instant) {
+        let nanos = started.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> OpTimings {
+        OpTimings {
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Messages exchanged between `KvsClient` and `KvsServer`.
+//!
+//! Every message is the protocol version byte, a little-endian `u32` length and
+//! the message as json. A message of any other version is rejected before its
+//! payload is parsed, as its layout can't be known.
+//!
+//! Requests can be pipelined: a client may send any number of them before reading
+//! the responses, which come back in the order of the requests.
+
+use crate::format;
+use crate::{KvsError, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+
+/// Version of the protocol spoken by this crate.
+pub const VERSION: u8 = 1;
+
+/// Request sent from a client to the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Set the value of a key.
+    Set {
+        /// Key to set
+        key: String,
+        /// Value to store under the key
+        value: String,
+    },
+    /// Get the value of a key.
+    Get {
+        /// Key to look up
+        key: String,
+    },
+    /// Remove a key.
+    Rm {
+        /// Key to remove
+        key: String,
+    },
+}
+
+/// Response sent back by the server for every request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// The value of the key asked for by a `Get`, `None` if it isn't set.
+    Value(Option<String>),
+    /// A `Set` or `Rm` succeeded.
+    Ok,
+    /// The request failed with the given message.
+    Err(String),
+}
+
+/// Writes a message to the stream and flushes it.
+pub fn write_message<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    buffer_message(writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a message to the stream without flushing it, so that several messages
+/// can go out together.
+pub fn buffer_message<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads the next message from the stream, `None` once the other side has closed it.
+///
+/// Returns `KvsError::ProtocolVersion` for a message of another protocol version.
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut version = [0; 1];
+    match reader.read_exact(&mut version) {
+        // the stream ended between two messages
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    if version[0] != VERSION {
+        return Err(KvsError::ProtocolVersion(version[0]));
+    }
+    match format::read_unchecked_frame(reader)? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+    }
+}
@@ -0,0 +1,90 @@
+use crate::format::{Codec, HEADER_LEN, PREFIX_LEN};
+use crate::storage::Storage;
+use crate::wal::Commands;
+use crate::Result;
+use std::path::Path;
+
+/// What `KvStore::repair` does with a damaged record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairMode {
+    /// Skip over it to the next intact record, then compact the log so that the
+    /// damage is gone from disk. Every intact record is kept.
+    Skip,
+    /// Cut its segment short where it starts, dropping it and the rest of the
+    /// segment. Later segments are kept.
+    Truncate,
+}
+
+/// What was found by `KvStore::repair`.
+///
+/// A damaged stretch of the log counts once in `skipped`, however many records it
+/// held. Its bytes are counted in `dropped_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RepairReport {
+    /// Number of intact records read from the log.
+    pub scanned: u64,
+    /// Number of damaged stretches skipped or cut off.
+    pub skipped: u64,
+    /// Bytes of the log dropped as damaged.
+    pub dropped_bytes: u64,
+}
+
+// The intact commands of a segment, in log order
+pub(crate) struct Scan {
+    pub(crate) commands: Vec<Commands>,
+    pub(crate) report: RepairReport,
+    // offset of the first damaged record, if there is one
+    pub(crate) first_damage: Option<usize>,
+}
+
+// Read every intact record of a segment. With `resync` a damaged record is skipped
+// by looking for the next offset an intact record starts at, otherwise the scan
+// stops at the first one.
+pub(crate) fn scan_segment(
+    storage: &Storage,
+    path: &Path,
+    codec: &Codec,
+    resync: bool,
+) -> Result<Scan> {
+    let data = storage.read(path)?;
+    let mut scan = Scan {
+        commands: Vec::new(),
+        report: RepairReport::default(),
+        first_damage: None,
+    };
+    let mut offset = HEADER_LEN.min(data.len());
+    while offset < data.len() {
+        if let Some((command, len)) = intact(&data[offset..], codec) {
+            scan.commands.push(command);
+            scan.report.scanned += 1;
+            offset += len;
+            continue;
+        }
+        scan.first_damage.get_or_insert(offset);
+        scan.report.skipped += 1;
+        let next = match resync {
+            true => (offset + 1..data.len())
+                .find(|&at| intact(&data[at..], codec).is_some())
+                .unwrap_or(data.len()),
+            false => data.len(),
+        };
+        scan.report.dropped_bytes += (next - offset) as u64;
+        offset = next;
+    }
+    Ok(scan)
+}
+
+// The command of a record at the start of `data` and the length of its frame, if it
+// is complete, matches its checksum and decodes
+fn intact(data: &[u8], codec: &Codec) -> Option<(Commands, usize)> {
+    let prefix = data.get(..PREFIX_LEN)?;
+    let mut len = [0; 4];
+    len.copy_from_slice(&prefix[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let payload = data.get(PREFIX_LEN..PREFIX_LEN.checked_add(len)?)?;
+    if crc32fast::hash(payload).to_le_bytes() != prefix[4..] {
+        return None;
+    }
+    let command = codec.decode(payload).ok()?;
+    Some((command, PREFIX_LEN + len))
+}
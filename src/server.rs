@@ -0,0 +1,284 @@
+use crate::protocol::{self, Request, Response};
+use crate::{KvsEngine, KvsError, Result, ThreadPool};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The `KvsServer` serves requests from `KvsClient`s over TCP.
+///
+/// Each request is a message of the `protocol` answered by a single response,
+/// a connection can carry any number of requests. Pipelined requests are answered
+/// in order. Connections are handled on the thread pool, each with its own handle
+/// to the engine.
+///
+/// With `max_connections` set, a connection beyond the limit waits in the accept
+/// backlog until another one closes. Once the backlog is full too, further
+/// connections are refused by the OS.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// # use kvs::{KvStore, KvsServer, Result, SharedQueueThreadPool, ThreadPool};
+/// # use std::env;
+/// # fn try_main() -> Result<()>{
+/// let store = KvStore::open(&env::current_dir()?)?;
+/// let pool = SharedQueueThreadPool::new(4)?;
+/// KvsServer::new(store, pool).run("127.0.0.1:4000")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    shutdown: ShutdownHandle,
+    max_connections: Option<usize>,
+    backlog: u32,
+}
+
+// Length of the accept queue unless configured, the one the standard library uses
+const DEFAULT_BACKLOG: u32 = 128;
+
+/// Stops a running `KvsServer`, see `KvsServer::shutdown_handle`.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    // signalled whenever a connection is closed
+    closed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stopping: bool,
+    // address the listener is bound to, once serving
+    addr: Option<SocketAddr>,
+    // open connections by id, to end them on shutdown
+    connections: HashMap<u64, TcpStream>,
+    next_id: u64,
+}
+
+impl ShutdownHandle {
+    /// Makes the server stop accepting connections and end the open ones once
+    /// their current request is answered. `KvsServer::serve` then flushes the
+    /// engine and returns.
+    ///
+    /// Returns at once, calling it again does nothing.
+    pub fn shutdown(&self) {
+        let mut state = self.0.state.lock().unwrap();
+        if state.stopping {
+            return;
+        }
+        state.stopping = true;
+        // the accept loop may be waiting for a connection to close
+        self.0.closed.notify_all();
+        // further requests read as the end of the connection, answers still go out
+        for stream in state.connections.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        // wake the accept loop with a connection of our own
+        if let Some(addr) = state.addr {
+            let _ = TcpStream::connect(reachable(addr));
+        }
+    }
+
+    // Wait until fewer than `max` connections are open, false if the server is
+    // stopping
+    fn wait_for_room(&self, max: Option<usize>) -> bool {
+        let state = self.0.state.lock().unwrap();
+        let state = self
+            .0
+            .closed
+            .wait_while(state, |state| {
+                !state.stopping && max.is_some_and(|max| state.connections.len() >= max)
+            })
+            .unwrap();
+        !state.stopping
+    }
+
+    // Register an accepted connection, None if the server is stopping
+    fn open(&self, stream: &TcpStream) -> Result<Option<Open>> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.stopping {
+            return Ok(None);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(id, stream.try_clone()?);
+        Ok(Some(Open(self.clone(), id)))
+    }
+
+    // Wait for every open connection to be closed
+    fn drain(&self) {
+        let state = self.0.state.lock().unwrap();
+        let _state = self
+            .0
+            .closed
+            .wait_while(state, |state| !state.connections.is_empty())
+            .unwrap();
+    }
+}
+
+// A registered connection, closed when dropped even if its job panics
+struct Open(ShutdownHandle, u64);
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        let shared = &(self.0).0;
+        shared.state.lock().unwrap().connections.remove(&self.1);
+        shared.closed.notify_all();
+    }
+}
+
+// Listen on the address with an accept queue of the given length
+fn bind(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // as the standard library does, so a restarted server can bind at once
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    Ok(socket.into())
+}
+
+// Address to connect to for reaching a listener bound to `addr`
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), v4.port())
+        }
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), v6.port())
+        }
+        addr => addr,
+    }
+}
+
+impl<E: KvsEngine + Clone + Send + 'static, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a `KvsServer` storing data in the engine and handling connections
+    /// on the pool.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            shutdown: ShutdownHandle::default(),
+            max_connections: None,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+
+    /// Sets the number of connections served at once, unlimited by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max.max(1));
+        self
+    }
+
+    /// Sets the length of the queue of connections waiting to be accepted, used by
+    /// `run`. Defaults to 128, the OS may cap it lower.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Returns a handle that stops the server, from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Binds to the address and serves connections until shut down.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let mut last = None;
+        for addr in addr.to_socket_addrs()? {
+            match bind(addr, self.backlog) {
+                Ok(listener) => return self.serve(listener),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))
+            .into())
+    }
+
+    /// Serves connections accepted by an already bound listener.
+    ///
+    /// A connection that fails is logged and dropped, the server keeps running.
+    /// Once shut down through a `ShutdownHandle`, waits for the open connections to
+    /// close and flushes the engine before returning.
+    pub fn serve(mut self, listener: TcpListener) -> Result<()> {
+        self.shutdown.0.state.lock().unwrap().addr = Some(listener.local_addr()?);
+        while self.shutdown.wait_for_room(self.max_connections) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let open = match self.shutdown.open(&stream) {
+                        Ok(Some(open)) => open,
+                        Ok(None) => break,
+                        Err(e) => {
+                            log::warn!("connection failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let mut engine = self.engine.clone();
+                    self.pool.spawn(move || {
+                        if let Err(e) = handle(&mut engine, stream) {
+                            log::warn!("error on connection: {}", e);
+                        }
+                        drop(open);
+                    });
+                }
+                Err(e) => log::warn!("connection failed: {}", e),
+            }
+        }
+        self.shutdown.drain();
+        self.engine.flush()
+    }
+}
+
+// Answer requests on the connection until the client closes it
+fn handle<E: KvsEngine>(engine: &mut E, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let request = match protocol::read_message::<Request>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // nothing more can be read from a client speaking another version
+            Err(e @ KvsError::ProtocolVersion(_)) => {
+                protocol::write_message(&mut writer, &Response::Err(e.to_string()))?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let (kind, key) = summary(&request);
+        log::debug!("{} {} from {}", kind, key, peer);
+        let response = match execute(engine, request) {
+            Ok(response) => response,
+            Err(e) => Response::Err(e.to_string()),
+        };
+        // responses to pipelined requests go out together once the client waits
+        protocol::buffer_message(&mut writer, &response)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+// The kind and key of a request, for logging without its value
+fn summary(request: &Request) -> (&'static str, &str) {
+    match request {
+        Request::Set { key, .. } => ("set", key),
+        Request::Get { key } => ("get", key),
+        Request::Rm { key } => ("rm", key),
+    }
+}
+
+fn execute<E: KvsEngine>(engine: &mut E, request: Request) -> Result<Response> {
+    match request {
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Ok),
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Rm { key } => engine.remove(key).map(|_| Response::Ok),
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{KvStats, KvStore, KvStoreConfig, KvsEngine, KvsError, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// File recording the number of shards a directory was created with
+const SHARDS_FILE: &str = "shards";
+
+/// The `ShardedKvStore` spreads its keys over several `KvStore`s, each with its own
+/// log and index in a subdirectory `shard-{n}`.
+///
+/// A key always goes to the shard picked by a hash of it, so every operation on a
+/// key touches a single shard, and a shard compacts its log without waiting on the
+/// others. The number of shards is fixed when the directory is first opened, as
+/// the keys would be looked up in the wrong shards otherwise.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, Result, ShardedKvStore};
+/// # use tempfile::TempDir;
+/// # fn try_main() -> Result<()>{
+/// let dir = TempDir::new()?;
+/// let mut store = ShardedKvStore::open(dir.path(), 4)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+}
+
+impl ShardedKvStore {
+    /// Opens the store in the directory with the given number of shards.
+    ///
+    /// Fails with `KvsError::ShardCount` if the directory was created with another
+    /// number of shards.
+    pub fn open(path: impl AsRef<Path>, shards: usize) -> Result<Self> {
+        ShardedKvStore::open_with(path, shards, KvStoreConfig::default())
+    }
+
+    /// Opens the store with every shard opened with the provided options.
+    ///
+    /// A configured compaction threshold applies to each shard on its own.
+    pub fn open_with(path: impl AsRef<Path>, shards: usize, config: KvStoreConfig) -> Result<Self> {
+        let path = path.as_ref();
+        let shards = shards.max(1);
+        check_shards(path, shards, !config.read_only)?;
+        let shards = (0..shards)
+            .map(|n| KvStore::open_with(shard_dir(path, n), config.clone()))
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+
+    /// Index of the shard a key is stored in.
+    pub fn shard_of(&self, key: &str) -> usize {
+        // crc32 rather than the std hasher, whose output may change between releases
+        crc32fast::hash(key.as_bytes()) as usize % self.shards.len()
+    }
+
+    /// The shards of the store, in the order of their index.
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+
+    /// Returns every live key across the shards, in no particular order.
+    pub fn keys(&self) -> Vec<String> {
+        self.shards.iter().flat_map(KvStore::keys).collect()
+    }
+
+    /// Returns an iterator over every live key/value pair across the shards, in no
+    /// particular order, see `KvStore::iter`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.shards.iter().flat_map(KvStore::iter)
+    }
+
+    /// Returns the number of live keys across the shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(KvStore::len).sum()
+    }
+
+    /// Returns `true` if no shard holds a live key.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(KvStore::is_empty)
+    }
+
+    /// Compacts the log of every shard in turn, returning the number of bytes
+    /// reclaimed altogether.
+    pub fn compact(&mut self) -> Result<u64> {
+        let mut reclaimed = 0;
+        for shard in &mut self.shards {
+            reclaimed += shard.compact()?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Writes out and syncs the log of every shard, see `KvStore::flush`.
+    pub fn flush(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(KvStore::flush)
+    }
+
+    /// Returns the counters of every shard added up, see `KvStore::stats`.
+    ///
+    /// `needs_compaction` is true if any shard needs one.
+    pub fn stats(&self) -> KvStats {
+        let mut total = KvStats::default();
+        for stats in self.shards.iter().map(KvStore::stats) {
+            total.live_keys += stats.live_keys;
+            total.records += stats.records;
+            total.dead_bytes += stats.dead_bytes;
+            total.log_size += stats.log_size;
+            total.compactions += stats.compactions;
+            total.needs_compaction |= stats.needs_compaction;
+            #[cfg(feature = "metrics")]
+            for (total, shard) in [
+                (&mut total.timings.set, stats.timings.set),
+                (&mut total.timings.get, stats.timings.get),
+                (&mut total.timings.remove, stats.timings.remove),
+                (&mut total.timings.compact, stats.timings.compact),
+            ] {
+                total.count += shard.count;
+                total.total += shard.total;
+            }
+        }
+        total
+    }
+
+    fn shard(&mut self, key: &str) -> &mut KvStore {
+        let n = self.shard_of(key);
+        &mut self.shards[n]
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        ShardedKvStore::flush(self)
+    }
+}
+
+fn shard_dir(path: &Path, n: usize) -> PathBuf {
+    path.join(format!("shard-{}", n))
+}
+
+// Record the number of shards on first use, failing if another number was recorded.
+// With `claim` false a missing record is left missing.
+fn check_shards(path: &Path, shards: usize, claim: bool) -> Result<()> {
+    let file = path.join(SHARDS_FILE);
+    match fs::read_to_string(&file) {
+        Ok(recorded) => match recorded.trim().parse() {
+            Ok(recorded) if recorded == shards => Ok(()),
+            Ok(recorded) => Err(KvsError::ShardCount(recorded)),
+            Err(_) => Err(KvsError::UnsupportedLog(format!(
+                "unreadable shard count {:?}",
+                recorded.trim()
+            ))),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if claim {
+                fs::create_dir_all(path)?;
+                fs::write(file, shards.to_string())?;
+            }
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
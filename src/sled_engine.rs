@@ -0,0 +1,70 @@
+use crate::engine;
+use crate::{KvsEngine, KvsError, Result};
+use sled::Db;
+use std::path::Path;
+
+/// The `SledKvsEngine` stores string key/value pairs in a `sled` database.
+///
+/// Every write is flushed to disk before returning.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, Result, SledKvsEngine};
+/// # use std::env;
+/// # fn try_main() -> Result<()>{
+/// let dir = env::current_dir()?;
+/// let mut store = SledKvsEngine::open(&dir)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Creates a `SledKvsEngine` from an opened `sled` database.
+    pub fn new(db: Db) -> Self {
+        SledKvsEngine { db }
+    }
+
+    /// Open the `sled` database stored in the provided directory
+    ///
+    /// Fails with `KvsError::WrongEngine` if the directory holds the data of another
+    /// engine.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        engine::check_engine(path, "sled", true)?;
+        Ok(SledKvsEngine::new(sled::open(path)?))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
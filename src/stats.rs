@@ -0,0 +1,27 @@
+#[cfg(feature = "metrics")]
+use crate::Timings;
+
+/// Counters describing a `KvStore` and its log, as returned by `KvStore::stats`.
+///
+/// `dead_bytes` estimates what a compaction would reclaim, the log size minus the
+/// records of live keys. Segment headers count as dead bytes, so it never quite
+/// drops to zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KvStats {
+    /// Number of keys that are set and haven't expired.
+    pub live_keys: u64,
+    /// Number of records across all log segments, overwritten and removed ones included.
+    pub records: u64,
+    /// Bytes of the log not holding the value of a live key.
+    pub dead_bytes: u64,
+    /// Size of all log segments together in bytes.
+    pub log_size: u64,
+    /// Number of compactions run since the store was opened.
+    pub compactions: u64,
+    /// True if the log has outgrown the compaction threshold, see
+    /// `KvStore::should_compact`.
+    pub needs_compaction: bool,
+    /// Time spent in each kind of operation, see `Timings`.
+    #[cfg(feature = "metrics")]
+    pub timings: Timings,
+}
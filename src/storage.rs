@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// Where the files of a log are kept, in a directory on disk or in buffers in memory
+// that live as long as the store. Every file the log reads or writes is opened
+// through this, so the log only ever sees something to read, write and seek.
+#[derive(Debug, Clone)]
+pub(crate) enum Storage {
+    Disk,
+    Memory(Arc<Mutex<HashMap<PathBuf, Buffer>>>),
+}
+
+// Contents of a file kept in memory. Handles share it, so a file removed while open
+// stays readable through them, as on disk.
+type Buffer = Arc<Mutex<Vec<u8>>>;
+
+impl Storage {
+    pub(crate) fn memory() -> Self {
+        Storage::Memory(Arc::default())
+    }
+
+    // Open an existing file for reads
+    pub(crate) fn open(&self, path: &Path) -> io::Result<Handle> {
+        match self {
+            Storage::Disk => Ok(Handle::Disk(File::open(path)?)),
+            Storage::Memory(files) => Ok(Handle::memory(Self::get(files, path)?, false)),
+        }
+    }
+
+    // Open an existing file for writes at any offset
+    pub(crate) fn open_write(&self, path: &Path) -> io::Result<Handle> {
+        match self {
+            Storage::Disk => Ok(Handle::Disk(OpenOptions::new().write(true).open(path)?)),
+            Storage::Memory(files) => Ok(Handle::memory(Self::get(files, path)?, false)),
+        }
+    }
+
+    // Open a file for appends, creating it if missing
+    pub(crate) fn open_append(&self, path: &Path) -> io::Result<Handle> {
+        match self {
+            Storage::Disk => Ok(Handle::Disk(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            Storage::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                let buffer = files.entry(path.to_path_buf()).or_default().clone();
+                Ok(Handle::memory(buffer, true))
+            }
+        }
+    }
+
+    // Create an empty file for writes, replacing any there is
+    pub(crate) fn create(&self, path: &Path) -> io::Result<Handle> {
+        match self {
+            Storage::Disk => Ok(Handle::Disk(File::create(path)?)),
+            Storage::Memory(files) => {
+                let buffer = Buffer::default();
+                let mut files = files.lock().unwrap();
+                files.insert(path.to_path_buf(), buffer.clone());
+                Ok(Handle::memory(buffer, false))
+            }
+        }
+    }
+
+    pub(crate) fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            Storage::Disk => fs::read(path),
+            Storage::Memory(files) => Ok(Self::get(files, path)?.lock().unwrap().clone()),
+        }
+    }
+
+    pub(crate) fn len(&self, path: &Path) -> io::Result<u64> {
+        match self {
+            Storage::Disk => Ok(fs::metadata(path)?.len()),
+            Storage::Memory(files) => Ok(Self::get(files, path)?.lock().unwrap().len() as u64),
+        }
+    }
+
+    pub(crate) fn exists(&self, path: &Path) -> bool {
+        match self {
+            Storage::Disk => path.exists(),
+            Storage::Memory(files) => files.lock().unwrap().contains_key(path),
+        }
+    }
+
+    pub(crate) fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self {
+            Storage::Disk => fs::rename(from, to),
+            Storage::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                let buffer = files.remove(from).ok_or_else(|| not_found(from))?;
+                files.insert(to.to_path_buf(), buffer);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Storage::Disk => fs::remove_file(path),
+            Storage::Memory(files) => match files.lock().unwrap().remove(path) {
+                Some(_) => Ok(()),
+                None => Err(not_found(path)),
+            },
+        }
+    }
+
+    // Paths of the files in a directory, in no particular order
+    pub(crate) fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        match self {
+            Storage::Disk => fs::read_dir(dir)?.map(|entry| Ok(entry?.path())).collect(),
+            Storage::Memory(files) => Ok(files
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|path| path.parent() == Some(dir))
+                .cloned()
+                .collect()),
+        }
+    }
+
+    fn get(files: &Mutex<HashMap<PathBuf, Buffer>>, path: &Path) -> io::Result<Buffer> {
+        let files = files.lock().unwrap();
+        files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+// An open file of the log
+#[derive(Debug)]
+pub(crate) enum Handle {
+    Disk(File),
+    Memory {
+        buffer: Buffer,
+        offset: u64,
+        append: bool, // writes go to the end whatever the offset
+    },
+}
+
+impl Handle {
+    fn memory(buffer: Buffer, append: bool) -> Self {
+        Handle::Memory {
+            buffer,
+            offset: 0,
+            append,
+        }
+    }
+
+    // A handle that reads as empty, for a log that has no file to open
+    pub(crate) fn empty() -> Self {
+        Handle::memory(Buffer::default(), false)
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        match self {
+            Handle::Disk(file) => Ok(file.metadata()?.len()),
+            Handle::Memory { buffer, .. } => Ok(buffer.lock().unwrap().len() as u64),
+        }
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.set_len(len),
+            Handle::Memory { buffer, .. } => {
+                buffer.lock().unwrap().resize(len as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    // Sync the contents to the storage device, nothing to do in memory
+    pub(crate) fn sync_data(&self) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.sync_data(),
+            Handle::Memory { .. } => Ok(()),
+        }
+    }
+
+    // Sync the contents and the metadata to the storage device
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.sync_all(),
+            Handle::Memory { .. } => Ok(()),
+        }
+    }
+}
+
+impl Read for Handle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Handle::Disk(file) => file.read(buf),
+            Handle::Memory { buffer, offset, .. } => {
+                let buffer = buffer.lock().unwrap();
+                let start = (*offset as usize).min(buffer.len());
+                let read = buf.len().min(buffer.len() - start);
+                buf[..read].copy_from_slice(&buffer[start..start + read]);
+                *offset += read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+impl Write for Handle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Handle::Disk(file) => file.write(buf),
+            Handle::Memory {
+                buffer,
+                offset,
+                append,
+            } => {
+                let mut buffer = buffer.lock().unwrap();
+                if *append {
+                    *offset = buffer.len() as u64;
+                }
+                let start = *offset as usize;
+                if buffer.len() < start + buf.len() {
+                    buffer.resize(start + buf.len(), 0);
+                }
+                buffer[start..start + buf.len()].copy_from_slice(buf);
+                *offset += buf.len() as u64;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Handle::Disk(file) => file.flush(),
+            Handle::Memory { .. } => Ok(()),
+        }
+    }
+}
+
+impl Seek for Handle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Handle::Disk(file) => file.seek(pos),
+            Handle::Memory { buffer, offset, .. } => {
+                let len = buffer.lock().unwrap().len() as i64;
+                let target = match pos {
+                    SeekFrom::Start(to) => Some(to as i64),
+                    SeekFrom::End(by) => len.checked_add(by),
+                    SeekFrom::Current(by) => (*offset as i64).checked_add(by),
+                };
+                match target {
+                    Some(target) if target >= 0 => {
+                        *offset = target as u64;
+                        Ok(*offset)
+                    }
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "seek to a negative offset",
+                    )),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{KvsError, Result};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A pool of threads running jobs handed to it.
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads.
+    ///
+    /// Fails with `KvsError::NoThreads` for a pool of no threads, or an error if any
+    /// of the threads fail to start.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs the job on a thread of the pool.
+    ///
+    /// A job that panics doesn't take its thread out of the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` whose threads take jobs from a single shared queue.
+///
+/// Dropping the pool lets the threads finish the jobs already queued, then exit.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // jobs would queue up with nothing to run them
+        if threads == 0 {
+            return Err(KvsError::NoThreads);
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let worker = Worker(receiver.clone());
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the queue only closes once every worker is gone, which a panic can't cause
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no threads left");
+    }
+}
+
+// Thread of the pool, replaced by a new one if a job panics on it
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    fn run(self) {
+        loop {
+            // the queue is unlocked again before the job runs
+            let job = self.0.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                // the pool was dropped
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker(self.0.clone());
+            if let Err(e) = thread::Builder::new().spawn(move || worker.run()) {
+                log::error!("failed to replace a thread of the pool: {}", e);
+            }
+        }
+    }
+}
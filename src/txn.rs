@@ -0,0 +1,81 @@
+use crate::wal::Commands;
+use crate::{KvStore, Result};
+use std::fmt;
+
+/// Writes to a `KvStore` that take effect together, made by `KvStore::transaction`.
+///
+/// Nothing is written until `commit`, dropping the batch instead discards it.
+/// The writes go to the log as a single group closed by a commit marker, a group
+/// cut short by a crash is ignored when the log is opened again, so either every
+/// write of the batch survives or none does.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()>{
+/// let mut store = KvStore::open_in_memory()?;
+/// store.set("from".to_owned(), "10".to_owned())?;
+/// let mut txn = store.transaction();
+/// txn.remove("from".to_owned()).set("to".to_owned(), "10".to_owned());
+/// txn.commit()?;
+/// assert_eq!(store.get("to")?, Some("10".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct TxnBatch<'a> {
+    store: &'a mut KvStore,
+    commands: Vec<Commands>,
+}
+
+// values are left out, as for the store
+impl fmt::Debug for TxnBatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TxnBatch")
+            .field("store", &self.store)
+            .field("writes", &self.commands.len())
+            .finish()
+    }
+}
+
+impl<'a> TxnBatch<'a> {
+    pub(crate) fn new(store: &'a mut KvStore) -> Self {
+        TxnBatch {
+            store,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Sets the value of a key once the batch is committed.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.commands.push(Commands::Set(key, value));
+        self
+    }
+
+    /// Removes a key once the batch is committed.
+    ///
+    /// The commit fails with `KvsError::KeyNotFound` if the key doesn't exist by
+    /// then, counting the writes of the batch before it.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.commands.push(Commands::Rm(key));
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Returns true if nothing was written to the batch.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Applies every write of the batch, or none of them if this fails.
+    ///
+    /// The writes are checked against the size limits and the removals against the
+    /// keys of the store before anything is appended.
+    pub fn commit(self) -> Result<()> {
+        self.store.commit(self.commands)
+    }
+}
@@ -0,0 +1,56 @@
+use crate::wal::{Index, Position};
+
+/// What was found by `KvStore::verify`, comparing the index held in memory with one
+/// rebuilt by replaying the log.
+///
+/// Keys are given as bytes, as in `ChangeEvent`, and listed in sorted order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    /// Number of keys the replay found set and not expired.
+    pub on_disk: u64,
+    /// Number of keys in the index that are set and not expired.
+    pub indexed: u64,
+    /// Keys the log holds a live record of that are missing from the index.
+    pub missing_from_index: Vec<Vec<u8>>,
+    /// Keys in the index that the log holds no live record of.
+    pub missing_from_log: Vec<Vec<u8>>,
+    /// Keys the index points at another record for than the latest one in the log.
+    pub misplaced: Vec<Vec<u8>>,
+}
+
+impl VerifyReport {
+    /// Returns true if the index matches the log, key for key.
+    pub fn is_consistent(&self) -> bool {
+        self.missing_from_index.is_empty()
+            && self.missing_from_log.is_empty()
+            && self.misplaced.is_empty()
+    }
+}
+
+// Compare the positions of the index with those of a replay of the log, keys that
+// expired by `now` only count towards neither total
+pub(crate) fn compare(
+    indexed: impl Iterator<Item = (Vec<u8>, Position)>,
+    mut replayed: Index,
+    now: u64,
+) -> VerifyReport {
+    let mut report = VerifyReport {
+        on_disk: replayed.values().filter(|p| !p.expired(now)).count() as u64,
+        ..VerifyReport::default()
+    };
+    for (key, position) in indexed {
+        if !position.expired(now) {
+            report.indexed += 1;
+        }
+        match replayed.remove(&key) {
+            Some(found) if found == position => (),
+            Some(_) => report.misplaced.push(key),
+            None => report.missing_from_log.push(key),
+        }
+    }
+    report.missing_from_index = replayed.into_keys().collect();
+    report.missing_from_index.sort();
+    report.missing_from_log.sort();
+    report.misplaced.sort();
+    report
+}
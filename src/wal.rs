@@ -0,0 +1,1774 @@
+use serde::{Deserialize, Serialize};
+
+use crate::appender::{Appender, Pending};
+use crate::format::{
+    self, Codec, Layout, RecordCodec, HEADER_LEN, PREFIX_LEN, UNCHECKED_PREFIX_LEN,
+};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::storage::{Handle, Storage};
+use crate::watch::Subscribers;
+use crate::{Clock, CompactionMode, KvStoreConfig, KvsError, LogFormat, Result, SyncMode};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// Where the record holding the latest value for a key lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) segment: u64,
+    pub(crate) start: usize,
+    pub(crate) len: usize,
+    pub(crate) value_len: usize, // bytes of the value itself, 0 for a removal
+    pub(crate) expires: Option<u64>, // unix seconds after which the value is gone
+}
+
+impl Position {
+    pub(crate) fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+pub(crate) type Index = HashMap<Vec<u8>, Position>;
+
+// Log file of versions before the log was split into segments
+const LEGACY_LOG: &str = "log.txt";
+
+// The segments of one log in a directory, named `{id}.log`, or `{name}.{id}.log`
+// for a named log, a higher id holds newer records
+#[derive(Debug, Clone)]
+pub(crate) struct Segments {
+    dir: PathBuf,
+    name: Option<String>,
+    pub(crate) custom: Option<Arc<dyn RecordCodec>>, // reads and writes the records if set
+    pub(crate) storage: Storage,                     // the files are opened through this
+}
+
+impl Segments {
+    pub(crate) fn new(
+        dir: &Path,
+        name: Option<String>,
+        custom: Option<Arc<dyn RecordCodec>>,
+        storage: Storage,
+    ) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            name,
+            custom,
+            storage,
+        }
+    }
+
+    // How a segment, or any other log file, is laid out
+    pub(crate) fn detect(&self, path: &Path) -> Result<Layout> {
+        format::detect(&self.storage, path, self.custom.as_ref())
+    }
+
+    // How the records of a segment in the current layout are encoded
+    pub(crate) fn codec(&self, id: u64) -> Result<Codec> {
+        let path = self.path(id);
+        match self.detect(&path)? {
+            Layout::Framed(codec) => Ok(codec),
+            _ => Err(KvsError::UnsupportedLog(format!(
+                "{} has not been migrated",
+                path.display()
+            ))),
+        }
+    }
+
+    pub(crate) fn path(&self, id: u64) -> PathBuf {
+        match &self.name {
+            Some(name) => self.dir.join(format!("{}.{}.log", name, id)),
+            None => self.dir.join(format!("{}.log", id)),
+        }
+    }
+
+    // Hint file listing the records of a compacted segment, see `write_hint`
+    pub(crate) fn hint(&self, id: u64) -> PathBuf {
+        self.path(id).with_extension("hint")
+    }
+
+    // Lock file held by the store that has the log open
+    pub(crate) fn lock(&self) -> PathBuf {
+        match &self.name {
+            Some(name) => self.dir.join(format!("{}.lock", name)),
+            None => self.dir.join("kvs.lock"),
+        }
+    }
+
+    // The log written before segments were introduced, only the unnamed log has one
+    pub(crate) fn legacy(&self) -> Option<PathBuf> {
+        match self.name {
+            Some(_) => None,
+            None => Some(self.dir.join(LEGACY_LOG)),
+        }
+    }
+
+    // Ids of the segments in the directory, oldest first,
+    // segments of logs with another name are skipped
+    pub(crate) fn ids(&self) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for path in self.storage.list(&self.dir)? {
+            if path.extension().is_some_and(|ext| ext == "log") {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| self.id(stem))
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn id(&self, stem: &str) -> Option<u64> {
+        let id = match &self.name {
+            Some(name) => stem.strip_prefix(name.as_str())?.strip_prefix('.')?,
+            None => stem,
+        };
+        id.parse().ok()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Wal {
+    pub(crate) size: u64,        // current size of all segments in bytes
+    pub(crate) records: u64,     // number of records across all segments
+    pub(crate) live: u64,        // bytes of the records the index points at
+    pub(crate) compactions: u64, // compactions run since the log was opened
+    /// Size limit in bytes for all segments before compaction should occur
+    threshold: Option<u64>,
+    /// Share of the log that must be dead before it is compacted
+    dead_ratio: Option<f64>,
+    compaction: CompactionMode,
+    /// Size in bytes after which appends roll over to a new segment
+    segment_size: u64,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    writer: BufWriter<Handle>, // appends to the active segment go through the buffer
+    appender: Option<Appender>, // or are handed to a writer thread of their own
+    sync: SyncMode,
+    unsynced: u64,              // writes flushed since the last sync
+    pub(crate) active: u64,     // id of the segment being appended to
+    pub(crate) active_len: u64, // EOF byte of the active segment
+    pub(crate) format: LogFormat,
+    compress: bool, // compress the records of compacted segments
+    pub(crate) clock: Arc<dyn Clock>,
+    oldest: Arc<AtomicU64>, // segments below this id have been compacted away
+    pub(crate) segments: Segments,
+    pub(crate) read_only: bool, // nothing is ever written, the log is opened for reads
+    pub(crate) cursor: Option<Cursor>, // where an incremental compaction carries on
+    pub(crate) redact_values: bool, // values are kept out of the operation logging
+    pub(crate) subscribers: Subscribers, // told about every key set or removed
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Arc<Metrics>, // timings of the operations on the store
+    _lock: Option<File>,        // released when closed, a log in memory has none
+}
+
+impl Wal {
+    // Open the newest segment for appends, starting the first if there are none.
+    // The log is locked against other stores first, until the `Wal` is dropped.
+    // A read-only log only takes a shared lock, and opens the newest segment for
+    // reads if there is one. With a background writer the frames it hasn't written
+    // out yet are kept in `pending`.
+    pub(crate) fn new(
+        segments: Segments,
+        config: &KvStoreConfig,
+        oldest: Arc<AtomicU64>,
+        pending: Pending,
+    ) -> Result<Self> {
+        // a log in memory belongs to the store alone, there is nothing to lock
+        let lock = match segments.storage {
+            Storage::Disk => Some(lock_log(&segments.lock(), config.read_only)?),
+            Storage::Memory(_) => None,
+        };
+        let ids = segments.ids()?;
+        let active = ids.last().copied().unwrap_or(1);
+        let handle = if !config.read_only {
+            segments.storage.open_append(&segments.path(active))?
+        } else if ids.is_empty() {
+            // nothing is ever written through it, there is just no segment to open
+            Handle::empty()
+        } else {
+            segments.storage.open(&segments.path(active))?
+        };
+        let len = handle.len()?;
+        let appender = match config.background_writer && !config.read_only {
+            true => Some(Appender::spawn(segments.clone(), config.sync, pending)?),
+            false => None,
+        };
+        let mut wal = Self {
+            size: len,
+            records: 0,
+            live: 0,
+            compactions: 0,
+            threshold: config.threshold,
+            dead_ratio: config.dead_ratio,
+            compaction: config.compaction,
+            segment_size: config.segment_size,
+            max_key_bytes: config.max_key_bytes,
+            max_value_bytes: config.max_value_bytes,
+            writer: BufWriter::new(handle),
+            appender,
+            sync: config.sync,
+            unsynced: 0,
+            active,
+            active_len: len,
+            format: config.format,
+            compress: config.compress,
+            clock: config.clock.clone(),
+            oldest,
+            segments,
+            read_only: config.read_only,
+            cursor: None,
+            redact_values: config.redact_values,
+            subscribers: Subscribers::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            _lock: lock,
+        };
+        if len == 0 && !wal.read_only {
+            wal.write_header()?;
+        }
+        Ok(wal)
+    }
+
+    // The active segment is never compressed, to keep appends fast
+    pub(crate) fn active_codec(&self) -> Codec {
+        Codec::new(self.format, false, self.segments.custom.clone())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        self.writer.write_all(&self.active_codec().header())?;
+        self.writer.flush()?;
+        self.size += HEADER_LEN as u64;
+        self.active_len = HEADER_LEN as u64;
+        Ok(())
+    }
+
+    // Open every segment for a replay of the log, holding the handles keeps
+    // segments deleted by a later compaction readable
+    pub(crate) fn records(&self) -> Result<Records> {
+        self.settle(false)?;
+        Records::open(&self.segments)
+    }
+
+    // append a command to the active segment's buffer, returning where it was written,
+    // it is only readable from the segment once flushed
+    pub(crate) fn append(&mut self, command: &Commands) -> Result<Position> {
+        self.writable()?;
+        self.check_size(command.key(), command.value_len())?;
+        if self.active_len >= self.segment_size {
+            self.roll()?;
+        }
+        let data = encode(&self.active_codec(), command)?;
+        self.write_frame(command, data)
+    }
+
+    // Append the commands as a transaction between a `Begin` and a `Commit` marker,
+    // returning where each was written. The transaction is never split across
+    // segments, so one cut short by a crash is always at the end of the log.
+    // Everything is encoded before anything is written, a command that fails to
+    // encode leaves nothing behind.
+    pub(crate) fn append_group(&mut self, commands: &[Commands]) -> Result<Vec<Position>> {
+        self.writable()?;
+        for command in commands {
+            self.check_size(command.key(), command.value_len())?;
+        }
+        let codec = self.active_codec();
+        let (begin, commit) = (Commands::Begin(commands.len() as u64), Commands::Commit);
+        let frames = commands
+            .iter()
+            .map(|command| Ok((command, encode(&codec, command)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let (begin_data, commit_data) = (encode(&codec, &begin)?, encode(&codec, &commit)?);
+        if self.active_len >= self.segment_size {
+            self.roll()?;
+        }
+        self.write_frame(&begin, begin_data)?;
+        let positions = frames
+            .into_iter()
+            .map(|(command, data)| self.write_frame(command, data))
+            .collect::<Result<_>>()?;
+        self.write_frame(&commit, commit_data)?;
+        Ok(positions)
+    }
+
+    // Write an encoded command at the end of the active segment
+    fn write_frame(&mut self, command: &Commands, data: Vec<u8>) -> Result<Position> {
+        let start = self.end()?;
+        let len = data.len();
+        match &self.appender {
+            Some(appender) => {
+                appender.append(self.active, start as usize, self.active_codec(), data)?
+            }
+            None => self.writer.write_all(&data)?,
+        }
+        let position = Position {
+            segment: self.active,
+            start: start as usize,
+            len,
+            value_len: command.value_len(),
+            expires: command.expires(),
+        };
+        self.size += len as u64;
+        self.records += 1;
+        self.active_len = start + len as u64;
+        Ok(position)
+    }
+
+    // Append a `Set` of the key to the value read from the reader, copying the value
+    // into the segment as it is read. Only for the bincode format, whose records
+    // `ValueReader` knows the layout of.
+    // The prefix and the value length are written as placeholders and filled in once
+    // the value has been copied, a length running past the end of the segment makes
+    // a crash part way through look like any other torn tail. A failure truncates the
+    // segment back to where the record started.
+    pub(crate) fn append_from(&mut self, key: &str, value: &mut dyn Read) -> Result<Position> {
+        self.writable()?;
+        self.check_size(key.as_bytes(), 0)?;
+        if self.active_len >= self.segment_size {
+            self.roll()?;
+        }
+        self.settle(false)?;
+        self.writer.flush()?;
+        let start = self.end()?;
+        match self.copy_record(start, key, value) {
+            Ok((len, value_len)) => {
+                self.size += len;
+                self.records += 1;
+                self.active_len = start + len;
+                Ok(Position {
+                    segment: self.active,
+                    start: start as usize,
+                    len: len as usize,
+                    value_len: value_len as usize,
+                    expires: None,
+                })
+            }
+            Err(e) => {
+                // drop whatever is still buffered rather than let it land after the truncation
+                let path = self.segments.path(self.active);
+                let handle = self.segments.storage.open_append(&path)?;
+                let _ = mem::replace(&mut self.writer, BufWriter::new(handle)).into_parts();
+                self.segments.storage.open_write(&path)?.set_len(start)?;
+                Err(e)
+            }
+        }
+    }
+
+    // Write a streamed record at the end of the active segment, returning its length
+    // and that of the value
+    fn copy_record(&mut self, start: u64, key: &str, value: &mut dyn Read) -> Result<(u64, u64)> {
+        let mut head = SET_TAG.to_le_bytes().to_vec();
+        head.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        head.extend_from_slice(key.as_bytes());
+        self.writer.write_all(&u32::MAX.to_le_bytes())?;
+        self.writer.write_all(&[0; 4])?;
+        self.writer.write_all(&head)?;
+        self.writer.write_all(&[0; 8])?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut utf8 = Utf8Check::default();
+        let mut buf = [0; 64 * 1024];
+        let mut value_len = 0u64;
+        loop {
+            let n = match value.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            value_len += n as u64;
+            self.check_size(key.as_bytes(), value_len as usize)?;
+            utf8.update(&buf[..n])?;
+            hasher.update(&buf[..n]);
+            self.writer.write_all(&buf[..n])?;
+        }
+        utf8.finish()?;
+        self.writer.flush()?;
+
+        let payload_len = head.len() as u64 + 8 + value_len;
+        if payload_len >= u32::MAX as u64 {
+            return Err(KvsError::ValueTooLarge {
+                limit: u32::MAX as u64 - 1 - (head.len() as u64 + 8),
+                actual: value_len,
+            });
+        }
+        let copied = value_len;
+        let value_len = value_len.to_le_bytes();
+        // the checksum covers the value length, which comes before the value
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&head);
+        checksum.update(&value_len);
+        checksum.combine(&hasher);
+        let mut prefix = (payload_len as u32).to_le_bytes().to_vec();
+        prefix.extend_from_slice(&checksum.finalize().to_le_bytes());
+
+        // appends ignore the offset, so patch through a handle of its own
+        let mut patch = self
+            .segments
+            .storage
+            .open_write(&self.segments.path(self.active))?;
+        patch.seek(SeekFrom::Start(start))?;
+        patch.write_all(&prefix)?;
+        patch.seek(SeekFrom::Start(start + (PREFIX_LEN + head.len()) as u64))?;
+        patch.write_all(&value_len)?;
+        Ok((PREFIX_LEN as u64 + payload_len, copied))
+    }
+
+    // Offset the next append to the active segment lands at, taken from the length
+    // of the segment and what is still buffered for it rather than a running count
+    // that could drift from the file. A background writer may not have caught up
+    // with the running count yet.
+    fn end(&self) -> Result<u64> {
+        if self.appender.is_some() {
+            return Ok(self.active_len);
+        }
+        let len = self.writer.get_ref().len()?;
+        Ok(len + self.writer.buffer().len() as u64)
+    }
+
+    // Seal the active segment and start appending to the next one
+    pub(crate) fn roll(&mut self) -> Result<()> {
+        self.settle(false)?;
+        self.writer.flush()?;
+        if self.sync != SyncMode::Never {
+            self.writer.get_ref().sync_data()?;
+        }
+        // the next segment is only switched to once it exists, so a failure leaves
+        // appends going to the segment the positions say they do
+        let handle = self
+            .segments
+            .storage
+            .open_append(&self.segments.path(self.active + 1))?;
+        self.active += 1;
+        self.writer = BufWriter::new(handle);
+        self.unsynced = 0;
+        self.write_header()
+    }
+
+    // Write a single segment holding a `Set` per live key, returning its id and
+    // the index into it. Appends carry on in a fresh segment after it.
+    // The records go to a temporary file that is renamed into place once
+    // complete, so a failure part way through leaves the existing segments intact.
+    // The segments it replaces are left for `retire` once the index is in place.
+    // With compression enabled each record of the segment is compressed on its own,
+    // so a record can still be read without the rest of the segment.
+    pub(crate) fn rewrite(&mut self, live: Live) -> Result<(u64, Index)> {
+        let mut out = self.start_rewrite()?;
+        for (k, (v, expires)) in live.into_iter() {
+            let value_len = v.len();
+            let command = Commands::set(k.clone(), v, expires);
+            let data = encode(&out.codec, &command)?;
+            out.push(k, &data, value_len, expires)?;
+        }
+        self.finish_rewrite(out)
+    }
+
+    // Rewrite the records at the positions into a single segment as `rewrite` does,
+    // reading them one at a time in log order so that only a record at a time is
+    // held in memory. A record already encoded the way the compacted segment is has
+    // its frame copied over as it is, without decoding the value.
+    pub(crate) fn rewrite_positions(
+        &mut self,
+        mut live: Vec<(Vec<u8>, Position)>,
+    ) -> Result<(u64, Index)> {
+        let mut out = self.start_rewrite()?;
+        live.sort_unstable_by_key(|(_, position)| (position.segment, position.start));
+        // segment being read, its codec and the offset the reader is at
+        let mut source: Option<(u64, Codec, BufReader<Handle>, u64)> = None;
+        for (key, position) in live {
+            let (segment, start) = (position.segment, position.start as u64);
+            if source.as_ref().is_none_or(|(id, ..)| *id != segment) {
+                let path = self.segments.path(segment);
+                let reader = BufReader::new(self.segments.storage.open(&path)?);
+                source = Some((segment, self.segments.codec(segment)?, reader, 0));
+            }
+            let (_, codec, reader, at) = source.as_mut().expect("source was just opened");
+            if *at != start {
+                reader.seek(SeekFrom::Start(start))?;
+            }
+            let mut frame = vec![0; position.len];
+            reader
+                .read_exact(&mut frame)
+                .map_err(|e| read_failed(&key, position, e.into()))?;
+            *at = start + frame.len() as u64;
+            let payload = format::frame_payload(&frame, segment, start)
+                .and_then(|payload| {
+                    payload.ok_or_else(|| {
+                        KvsError::MisplacedRecord(format!("no frame of {} bytes", position.len))
+                    })
+                })
+                .map_err(|e| read_failed(&key, position, e))?;
+            if codec.same_records(&out.codec) && codec.compressed == out.codec.compressed {
+                out.push(key, &frame, position.value_len, position.expires)?;
+            } else {
+                let command = codec
+                    .decode(payload)
+                    .map_err(|e| read_failed(&key, position, e))?;
+                let data = encode(&out.codec, &command)?;
+                out.push(key, &data, position.value_len, position.expires)?;
+            }
+        }
+        self.finish_rewrite(out)
+    }
+
+    // Open the temporary file of a compacted segment, after the segment being
+    // appended to is written out
+    fn start_rewrite(&mut self) -> Result<Rewrite> {
+        self.writable()?;
+        self.settle(false)?;
+        self.writer.flush()?;
+        let segment = self.active + 1;
+        let temp = self.segments.path(segment).with_extension("log.compact");
+        let codec = Codec::new(self.format, self.compress, self.segments.custom.clone());
+        let mut writer = BufWriter::new(self.segments.storage.create(&temp)?);
+        writer.write_all(&codec.header())?;
+        Ok(Rewrite {
+            segment,
+            temp,
+            codec,
+            writer,
+            map: HashMap::new(),
+            offset: HEADER_LEN,
+        })
+    }
+
+    // Move a complete compacted segment into place, with appends carrying on after it
+    fn finish_rewrite(&mut self, out: Rewrite) -> Result<(u64, Index)> {
+        let Rewrite {
+            segment: compacted,
+            temp,
+            mut writer,
+            map,
+            offset,
+            ..
+        } = out;
+        let target = self.segments.path(compacted);
+        writer.flush()?;
+        // the rename must not reach the disk before the records do
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        self.segments.storage.rename(&temp, &target)?;
+        // the segment is complete without it, a later open just replays it
+        if let Err(e) = write_hint(&self.segments, compacted, offset as u64, &map) {
+            log::warn!(
+                "failed to write the hint of log segment {}: {}",
+                compacted,
+                e
+            );
+        }
+
+        // the compacted segment replays after the ones it replaces, so a crash
+        // before they are all deleted only leaves redundant records behind
+        let handle = self
+            .segments
+            .storage
+            .open_append(&self.segments.path(compacted + 1))?;
+        self.active = compacted + 1;
+        self.writer = BufWriter::new(handle);
+        self.size = offset as u64;
+        self.records = map.len() as u64;
+        self.unsynced = 0;
+        // an incremental compaction in progress has nothing left to do
+        self.cursor = None;
+        self.write_header()?;
+        Ok((compacted, map))
+    }
+
+    // Delete the segments replaced by the compacted one
+    pub(crate) fn retire(&self, compacted: u64) {
+        // readers that still hold a position into them look the key up again
+        self.oldest.store(compacted, Ordering::SeqCst);
+        // a segment that fails to delete is swept up by the next compaction
+        for id in self.segments.ids().unwrap_or_default() {
+            if id < compacted {
+                let _ = self.segments.storage.remove(&self.segments.path(id));
+                let _ = self.segments.storage.remove(&self.segments.hint(id));
+            }
+        }
+    }
+
+    // Delete the oldest segment once an incremental compaction copied its live records
+    // out of it, `records` of them in all. The copies are synced first.
+    pub(crate) fn drop_oldest(&mut self, id: u64, records: u64) -> Result<()> {
+        self.sync()?;
+        let path = self.segments.path(id);
+        let len = self.segments.storage.len(&path)?;
+        // readers that still hold a position into it look the key up again
+        self.oldest.store(id + 1, Ordering::SeqCst);
+        self.size = self.size.saturating_sub(len);
+        self.records = self.records.saturating_sub(records);
+        let _ = self.segments.storage.remove(&self.segments.hint(id));
+        Ok(self.segments.storage.remove(&path)?)
+    }
+
+    // write out any buffered appends to the active segment,
+    // syncing them to the device as often as the sync mode asks for.
+    // A background writer does both on its own time.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if self.appender.is_some() {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncMode::Always => true,
+            SyncMode::Never => false,
+            SyncMode::EveryN(n) => self.unsynced >= n,
+        };
+        if due {
+            self.writer.get_ref().sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    // write out buffered appends without syncing them
+    pub(crate) fn write_out(&mut self) -> Result<()> {
+        self.settle(false)?;
+        Ok(self.writer.flush()?)
+    }
+
+    // write out buffered appends and sync everything to the device
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.settle(true)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // True if the number of bytes across all segments exceeds the threshold, and
+    // enough of them are dead with a ratio set. Never true when automatic
+    // compaction is disabled or the log is read-only
+    pub(crate) fn exceeds(&self) -> bool {
+        let dead = self.size.saturating_sub(self.live);
+        !self.read_only
+            && self.threshold.is_some_and(|t| self.size > t)
+            && self
+                .dead_ratio
+                .is_none_or(|ratio| dead as f64 > ratio * self.size as f64)
+    }
+
+    // True if the write that just finished should compact the log before returning
+    pub(crate) fn compact_inline(&self) -> bool {
+        self.compaction == CompactionMode::Inline && self.exceeds()
+    }
+
+    // Bytes to compact by as part of the write that just finished, if the log is
+    // compacted incrementally and a pass is due or in progress
+    pub(crate) fn step_due(&self) -> Option<u64> {
+        match self.compaction {
+            CompactionMode::Incremental(budget) if self.cursor.is_some() || self.exceeds() => {
+                Some(budget)
+            }
+            _ => None,
+        }
+    }
+
+    // Wait for the background writer, if any, to write out every queued append
+    fn settle(&self, sync: bool) -> Result<()> {
+        match &self.appender {
+            Some(appender) => appender.drain(sync),
+            None => Ok(()),
+        }
+    }
+
+    // Fail a write of a key or a value past the configured limits
+    pub(crate) fn check_size(&self, key: &[u8], value_len: usize) -> Result<()> {
+        if let Some(limit) = self.max_key_bytes.filter(|&limit| key.len() > limit) {
+            return Err(KvsError::KeyTooLarge {
+                limit: limit as u64,
+                actual: key.len() as u64,
+            });
+        }
+        if let Some(limit) = self.max_value_bytes.filter(|&limit| value_len > limit) {
+            return Err(KvsError::ValueTooLarge {
+                limit: limit as u64,
+                actual: value_len as u64,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+}
+
+fn encode(codec: &Codec, command: &Commands) -> Result<Vec<u8>> {
+    Ok(format::frame(&codec.encode(command)?))
+}
+
+// Lock the log against other stores, a read-only store only takes a shared lock
+fn lock_log(path: &Path, read_only: bool) -> Result<File> {
+    let lock = open_lock(path, read_only)?;
+    let locked = if read_only {
+        lock.try_lock_shared()
+    } else {
+        lock.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(KvsError::AlreadyLocked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+// A read-only store opens the lock file for reads, only creating it if missing
+fn open_lock(path: &Path, read_only: bool) -> Result<File> {
+    if read_only {
+        match File::open(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            lock => return Ok(lock?),
+        }
+    }
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?)
+}
+
+// Read handles to the segments, each store handle owns its own so that
+// concurrent reads don't share a cursor
+#[derive(Debug)]
+pub(crate) struct LogReader {
+    handles: HashMap<u64, (BufReader<Handle>, Codec)>, // opened on first read, always seek before reading
+    oldest: Arc<AtomicU64>,
+    segments: Segments,
+    pending: Pending, // frames the background writer hasn't written out yet
+}
+
+impl LogReader {
+    pub(crate) fn new(segments: Segments, oldest: Arc<AtomicU64>, pending: Pending) -> Self {
+        Self {
+            handles: HashMap::new(),
+            oldest,
+            segments,
+            pending,
+        }
+    }
+
+    // A reader holding a handle on every segment there is now, which keeps reading
+    // them after a compaction deletes them. The writer lock must be held while it
+    // is opened, with every append written out.
+    pub(crate) fn pinned(&self) -> Result<Self> {
+        let mut reader = Self::new(
+            self.segments.clone(),
+            Arc::new(AtomicU64::new(0)), // never retires a segment
+            self.pending.clone(),
+        );
+        for id in self.segments.ids()? {
+            let codec = self.segments.codec(id)?;
+            let handle = self.segments.storage.open(&self.segments.path(id))?;
+            reader.handles.insert(id, (BufReader::new(handle), codec));
+        }
+        Ok(reader)
+    }
+
+    // True if the position points into a segment deleted by compaction
+    pub(crate) fn retired(&self, position: Position) -> bool {
+        position.segment < self.oldest.load(Ordering::SeqCst)
+    }
+
+    // Read one command based off the position of its frame, the frame must span
+    // exactly the position and hold a command for the key
+    pub(crate) fn read_one(&mut self, key: &[u8], position: Position) -> Result<Commands> {
+        self.read_frame(key, position)
+            .map_err(|e| read_failed(key, position, e))
+    }
+
+    // Reader over the value held by the record at the position, `None` for a removal.
+    // An uncompressed bincode record is streamed from its own handle on the segment,
+    // any other is decoded into memory first.
+    pub(crate) fn value_reader(
+        &mut self,
+        key: &[u8],
+        position: Position,
+    ) -> Result<Option<ValueReader>> {
+        let path = self.segments.path(position.segment);
+        let codec = self
+            .segments
+            .codec(position.segment)
+            .map_err(|e| read_failed(key, position, e))?;
+        let pending = self
+            .pending
+            .contains_key(&(position.segment, position.start));
+        if codec.streams_values() && !pending {
+            return self
+                .segments
+                .storage
+                .open(&path)
+                .map_err(KvsError::from)
+                .and_then(|file| ValueReader::stream(file, key, position))
+                .map_err(|e| read_failed(key, position, e));
+        }
+        let (_, value) = self.read_one(key, position)?.into_parts();
+        Ok(value.map(|(v, _)| ValueReader::Buffered(io::Cursor::new(v))))
+    }
+
+    // Read the payload of the frame at the position from its segment
+    fn read_payload(&mut self, position: Position) -> Result<(Vec<u8>, Codec)> {
+        // handles on segments deleted by compaction are never read again
+        let oldest = self.oldest.load(Ordering::SeqCst);
+        self.handles.retain(|&id, _| id >= oldest);
+        let (handle, codec) = match self.handles.entry(position.segment) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let codec = self.segments.codec(position.segment)?;
+                let path = self.segments.path(position.segment);
+                let handle = self.segments.storage.open(&path)?;
+                entry.insert((BufReader::new(handle), codec))
+            }
+        };
+
+        handle.seek(SeekFrom::Start(position.start as u64))?;
+        let frame = &mut handle.take(position.len as u64);
+        let payload = format::read_frame(frame, position.segment, position.start as u64)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        Ok((payload, codec.clone()))
+    }
+
+    fn read_frame(&mut self, key: &[u8], position: Position) -> Result<Commands> {
+        let pending = self.pending.clone();
+        let (payload, codec) = match pending.get(&(position.segment, position.start)) {
+            Some(entry) => {
+                let (codec, frame) = entry.value();
+                let payload = format::read_frame(
+                    &mut frame.as_slice(),
+                    position.segment,
+                    position.start as u64,
+                )?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                (payload, codec.clone())
+            }
+            None => self.read_payload(position)?,
+        };
+        // a position that is off still lands on a well formed frame now and then
+        if PREFIX_LEN + payload.len() != position.len {
+            return Err(wrong_length(PREFIX_LEN + payload.len(), position));
+        }
+
+        let command = codec.decode(&payload)?;
+        if command.key() != key {
+            return Err(wrong_key(command.key()));
+        }
+
+        Ok(command)
+    }
+}
+
+fn read_failed(key: &[u8], position: Position, e: KvsError) -> KvsError {
+    KvsError::ReadFailed {
+        key: String::from_utf8_lossy(key).into_owned(),
+        segment: position.segment,
+        offset: position.start as u64,
+        source: Box::new(e),
+    }
+}
+
+// The position lands on a frame of another length than the one it was indexed with
+fn wrong_length(len: usize, position: Position) -> KvsError {
+    KvsError::MisplacedRecord(format!(
+        "frame of {} bytes where {} were expected",
+        len, position.len
+    ))
+}
+
+// The position lands on a record of another key
+fn wrong_key(found: &[u8]) -> KvsError {
+    KvsError::MisplacedRecord(format!("record of key {}", String::from_utf8_lossy(found)))
+}
+
+// Bincode variant indices of the commands holding a value, see `Commands`
+const SET_TAG: u32 = 0;
+const SET_WITH_TTL_TAG: u32 = 3;
+const SET_BYTES_TAG: u32 = 4;
+
+// The value of a record, either streamed from the segment or decoded up front
+pub(crate) enum ValueReader {
+    Streamed {
+        value: io::Take<BufReader<Handle>>,
+        hasher: crc32fast::Hasher,
+        checksum: u32,
+        trailer: u64, // bytes of the record after the value, such as the expiry
+    },
+    Buffered(io::Cursor<Vec<u8>>),
+}
+
+impl ValueReader {
+    // Read a bincode record up to the start of its value, `None` for a removal.
+    // A bincode `Set` is the variant index as a u32, then the key and the value,
+    // each as a u64 length followed by the bytes, and for `SetWithTtl` and
+    // `SetBytes` the expiry.
+    fn stream(file: Handle, key: &[u8], position: Position) -> Result<Option<Self>> {
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(position.start as u64))?;
+        let mut prefix = [0; PREFIX_LEN];
+        reader.read_exact(&mut prefix)?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&prefix[..4]);
+        let len = PREFIX_LEN + u32::from_le_bytes(len) as usize;
+        if len != position.len {
+            return Err(wrong_length(len, position));
+        }
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&prefix[4..]);
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut tag = [0; 4];
+        reader.read_exact(&mut tag)?;
+        hasher.update(&tag);
+        match u32::from_le_bytes(tag) {
+            SET_TAG | SET_WITH_TTL_TAG | SET_BYTES_TAG => (),
+            _ => return Ok(None),
+        }
+        let key_len = read_len(&mut reader, &mut hasher)?;
+        let mut found = Vec::new();
+        (&mut reader).take(key_len).read_to_end(&mut found)?;
+        hasher.update(&found);
+        if found != key {
+            return Err(wrong_key(&found));
+        }
+        let value_len = read_len(&mut reader, &mut hasher)?;
+        // whatever of the frame is left after the value
+        let trailer = (len as u64)
+            .checked_sub((PREFIX_LEN + 4 + 8 + 8) as u64 + key_len + value_len)
+            .ok_or_else(|| wrong_length(len, position))?;
+        Ok(Some(ValueReader::Streamed {
+            value: reader.take(value_len),
+            hasher,
+            checksum: u32::from_le_bytes(checksum),
+            trailer,
+        }))
+    }
+}
+
+// Validates UTF-8 that arrives in chunks, a character can be split between two
+#[derive(Default)]
+struct Utf8Check {
+    pending: Vec<u8>, // start of a character cut off at the end of the last chunk
+}
+
+impl Utf8Check {
+    fn update(&mut self, mut chunk: &[u8]) -> Result<()> {
+        if !self.pending.is_empty() {
+            // at most three more bytes complete the character
+            let take = chunk.len().min(4 - self.pending.len());
+            self.pending.extend_from_slice(&chunk[..take]);
+            match std::str::from_utf8(&self.pending) {
+                Ok(_) => chunk = &chunk[take..],
+                Err(e) if e.valid_up_to() > 0 => {
+                    // the character ended within the bytes taken
+                    let used = e.valid_up_to() - (self.pending.len() - take);
+                    chunk = &chunk[used..];
+                }
+                Err(e) if e.error_len().is_none() && take == chunk.len() => return Ok(()),
+                Err(_) => return Err(invalid_utf8()),
+            }
+            self.pending.clear();
+        }
+        match std::str::from_utf8(chunk) {
+            Ok(_) => Ok(()),
+            Err(e) if e.error_len().is_none() => {
+                self.pending.extend_from_slice(&chunk[e.valid_up_to()..]);
+                Ok(())
+            }
+            Err(_) => Err(invalid_utf8()),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.pending.is_empty() {
+            true => Ok(()),
+            false => Err(invalid_utf8()),
+        }
+    }
+}
+
+fn invalid_utf8() -> KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, "value is not valid UTF-8").into()
+}
+
+fn read_len(reader: &mut impl Read, hasher: &mut crc32fast::Hasher) -> Result<u64> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    hasher.update(&len);
+    Ok(u64::from_le_bytes(len))
+}
+
+impl Read for ValueReader {
+    // the checksum of a streamed record is only known once all of it has been read,
+    // a mismatch fails the read that reaches the end of the value
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (value, hasher, checksum, trailer) = match self {
+            ValueReader::Streamed {
+                value,
+                hasher,
+                checksum,
+                trailer,
+            } => (value, hasher, *checksum, *trailer),
+            ValueReader::Buffered(cursor) => return cursor.read(buf),
+        };
+        let n = value.read(buf)?;
+        hasher.update(&buf[..n]);
+        if n > 0 || buf.is_empty() {
+            return Ok(n);
+        }
+        if value.limit() > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut rest = Vec::new();
+        value.get_mut().take(trailer).read_to_end(&mut rest)?;
+        hasher.update(&rest);
+        if (rest.len() as u64) < trailer || hasher.clone().finalize() != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "value doesn't match the checksum of its record",
+            ));
+        }
+        Ok(0)
+    }
+}
+
+impl Clone for LogReader {
+    // the clone opens its own handles rather than sharing the cursors
+    fn clone(&self) -> Self {
+        Self::new(
+            self.segments.clone(),
+            self.oldest.clone(),
+            self.pending.clone(),
+        )
+    }
+}
+
+/// A record of the log, as replayed by `KvStore::log_records`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRecord {
+    /// The key was set to the value.
+    Set(String, String),
+    /// The key was set to the value, expiring at the given unix seconds.
+    SetWithTtl(String, String, u64),
+    /// The key was removed.
+    Remove(String),
+    /// The key was set to the value, one of them not valid UTF-8, expiring at the
+    /// given unix seconds if there is a time.
+    SetBytes(Vec<u8>, Vec<u8>, Option<u64>),
+    /// The key, not valid UTF-8, was removed.
+    RemoveBytes(Vec<u8>),
+    /// Start of a transaction made of the given number of records, which only took
+    /// effect if its `Commit` follows them.
+    Begin(u64),
+    /// End of a transaction, see `KvStore::transaction`.
+    Commit,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogRecord::Set(k, v) => write!(f, "SET {} {}", k, v),
+            LogRecord::SetWithTtl(k, v, expires) => {
+                write!(f, "SET {} {} EXPIRES {}", k, v, expires)
+            }
+            LogRecord::Remove(k) => write!(f, "RM {}", k),
+            LogRecord::SetBytes(k, v, expires) => {
+                write!(f, "SET {} {}", k.escape_ascii(), v.escape_ascii())?;
+                match expires {
+                    Some(expires) => write!(f, " EXPIRES {}", expires),
+                    None => Ok(()),
+                }
+            }
+            LogRecord::RemoveBytes(k) => write!(f, "RM {}", k.escape_ascii()),
+            LogRecord::Begin(records) => write!(f, "BEGIN {}", records),
+            LogRecord::Commit => write!(f, "COMMIT"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Commands {
+    Set(String, String),
+    Rm(String),
+    // Slot of a `Get` variant that was never written to the log, it keeps the
+    // bincode index of the variants after it and can't be constructed or decoded
+    Unused(Never),
+    SetWithTtl(String, String, u64), // expires at the given unix seconds
+    // A key or a value that isn't UTF-8, the string variants are written otherwise
+    SetBytes(Vec<u8>, Vec<u8>, Option<u64>),
+    RmBytes(Vec<u8>),
+    // Start of a transaction of the given number of records, which only take effect
+    // once the `Commit` following them is read
+    Begin(u64),
+    Commit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Never {}
+
+impl Commands {
+    // Set the key to the value, through the string variants if both are UTF-8
+    pub(crate) fn set(key: Vec<u8>, value: Vec<u8>, expires: Option<u64>) -> Self {
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(k), Ok(v)) => match expires {
+                Some(expires) => Commands::SetWithTtl(k, v, expires),
+                None => Commands::Set(k, v),
+            },
+            (k, v) => Commands::SetBytes(raw(k), raw(v), expires),
+        }
+    }
+
+    // Remove the key, through the string variant if it is UTF-8
+    pub(crate) fn rm(key: Vec<u8>) -> Self {
+        match String::from_utf8(key) {
+            Ok(k) => Commands::Rm(k),
+            Err(e) => Commands::RmBytes(e.into_bytes()),
+        }
+    }
+
+    // Key the command sets or removes. The markers of a transaction have none, the
+    // replay takes them out before looking at keys.
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Commands::Set(k, _) | Commands::SetWithTtl(k, _, _) | Commands::Rm(k) => k.as_bytes(),
+            Commands::SetBytes(k, _, _) | Commands::RmBytes(k) => k,
+            Commands::Begin(_) | Commands::Commit => &[],
+            Commands::Unused(never) => match *never {},
+        }
+    }
+
+    // Bytes of the value the command sets, 0 for a removal
+    pub(crate) fn value_len(&self) -> usize {
+        self.value().map_or(0, <[u8]>::len)
+    }
+
+    // Value the command sets the key to, `None` for a removal
+    pub(crate) fn value(&self) -> Option<&[u8]> {
+        match self {
+            Commands::Set(_, v) | Commands::SetWithTtl(_, v, _) => Some(v.as_bytes()),
+            Commands::SetBytes(_, v, _) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn expires(&self) -> Option<u64> {
+        match self {
+            Commands::SetWithTtl(_, _, expires) => Some(*expires),
+            Commands::SetBytes(_, _, expires) => *expires,
+            _ => None,
+        }
+    }
+
+    // The key, and the value it is set to along with its expiry, `None` for a removal
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Option<Value>) {
+        match self {
+            Commands::Set(k, v) => (k.into_bytes(), Some((v.into_bytes(), None))),
+            Commands::SetWithTtl(k, v, expires) => {
+                (k.into_bytes(), Some((v.into_bytes(), Some(expires))))
+            }
+            Commands::SetBytes(k, v, expires) => (k, Some((v, expires))),
+            Commands::Rm(k) => (k.into_bytes(), None),
+            Commands::RmBytes(k) => (k, None),
+            Commands::Begin(_) | Commands::Commit => (Vec::new(), None),
+        }
+    }
+
+    pub(crate) fn into_record(self) -> LogRecord {
+        match self {
+            Commands::Set(k, v) => LogRecord::Set(k, v),
+            Commands::SetWithTtl(k, v, expires) => LogRecord::SetWithTtl(k, v, expires),
+            Commands::Rm(k) => LogRecord::Remove(k),
+            Commands::SetBytes(k, v, expires) => LogRecord::SetBytes(k, v, expires),
+            Commands::RmBytes(k) => LogRecord::RemoveBytes(k),
+            Commands::Begin(records) => LogRecord::Begin(records),
+            Commands::Commit => LogRecord::Commit,
+        }
+    }
+
+    pub(crate) fn to_record(&self) -> LogRecord {
+        self.clone().into_record()
+    }
+
+    pub(crate) fn from_record(record: LogRecord) -> Self {
+        match record {
+            LogRecord::Set(k, v) => Commands::Set(k, v),
+            LogRecord::SetWithTtl(k, v, expires) => Commands::SetWithTtl(k, v, expires),
+            LogRecord::Remove(k) => Commands::Rm(k),
+            LogRecord::SetBytes(k, v, expires) => Commands::SetBytes(k, v, expires),
+            LogRecord::RemoveBytes(k) => Commands::RmBytes(k),
+            LogRecord::Begin(records) => Commands::Begin(records),
+            LogRecord::Commit => Commands::Commit,
+        }
+    }
+}
+
+fn raw(s: std::result::Result<String, FromUtf8Error>) -> Vec<u8> {
+    s.map_or_else(FromUtf8Error::into_bytes, String::into_bytes)
+}
+
+// Replay of every record in the log, oldest segment first
+pub(crate) struct Records {
+    segments: VecDeque<Replay>,
+}
+
+// Segment being replayed and the offset of its next record
+struct Replay {
+    reader: BufReader<Handle>,
+    codec: Codec,
+    segment: u64,
+    offset: u64,
+    records: u64, // replayed so far
+}
+
+impl Records {
+    pub(crate) fn open(segments: &Segments) -> Result<Self> {
+        let mut replays = VecDeque::new();
+        for id in segments.ids()? {
+            let codec = segments.codec(id)?;
+            let mut reader = BufReader::new(segments.storage.open(&segments.path(id))?);
+            reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+            replays.push_back(Replay {
+                reader,
+                codec,
+                segment: id,
+                offset: HEADER_LEN as u64,
+                records: 0,
+            });
+        }
+        Ok(Self { segments: replays })
+    }
+
+    // Next command along with the segment and offset of its frame,
+    // nothing follows an error since nothing past a damaged record can be trusted
+    pub(crate) fn next_command(&mut self) -> Option<(u64, u64, Result<Commands>)> {
+        loop {
+            let replay = self.segments.front_mut()?;
+            let (segment, offset) = (replay.segment, replay.offset);
+            let command = match format::read_frame(&mut replay.reader, segment, offset) {
+                Ok(Some(payload)) => {
+                    let record = replay.records;
+                    replay.offset += (PREFIX_LEN + payload.len()) as u64;
+                    replay.records += 1;
+                    replay
+                        .codec
+                        .decode(&payload)
+                        .map_err(|e| replay_failed(segment, offset as usize, record, e))
+                }
+                Ok(None) => {
+                    self.segments.pop_front();
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+            if command.is_err() {
+                self.segments.clear();
+            }
+            return Some((segment, offset, command));
+        }
+    }
+}
+
+impl Iterator for Records {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_command()?.2.map(Commands::into_record))
+    }
+}
+
+// Read every command of a log laid out as given,
+// the segment id is only used to report a damaged record
+pub(crate) fn read_commands(
+    storage: &Storage,
+    path: &Path,
+    segment: u64,
+    layout: Layout,
+) -> Result<Vec<Commands>> {
+    let mut commands = Vec::new();
+    match layout {
+        Layout::Empty => (),
+        Layout::Legacy => {
+            let mut stream =
+                serde_json::Deserializer::from_reader(BufReader::new(storage.open(path)?))
+                    .into_iter::<Commands>();
+            loop {
+                // where the previous record ended, whitespace before the next included
+                let offset = stream.byte_offset();
+                match stream.next() {
+                    Some(Ok(command)) => commands.push(command),
+                    Some(Err(e)) => {
+                        let record = commands.len() as u64;
+                        return Err(replay_failed(segment, offset, record, e.into()));
+                    }
+                    None => break,
+                }
+            }
+        }
+        Layout::Unchecked(format) => {
+            let mut reader = BufReader::new(storage.open(path)?);
+            reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+            let mut offset = HEADER_LEN;
+            while let Some(payload) = format::read_unchecked_frame(&mut reader)? {
+                let command = format
+                    .decode(&payload)
+                    .map_err(|e| replay_failed(segment, offset, commands.len() as u64, e))?;
+                commands.push(command);
+                offset += UNCHECKED_PREFIX_LEN + payload.len();
+            }
+        }
+        Layout::Framed(codec) => {
+            let mut reader = BufReader::new(storage.open(path)?);
+            reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+            let mut offset = HEADER_LEN;
+            while let Some(payload) = format::read_frame(&mut reader, segment, offset as u64)? {
+                let command = codec
+                    .decode(&payload)
+                    .map_err(|e| replay_failed(segment, offset, commands.len() as u64, e))?;
+                commands.push(command);
+                offset += PREFIX_LEN + payload.len();
+            }
+        }
+    }
+    Ok(commands)
+}
+
+// Where an incremental compaction carries on, the segments of the log are copied
+// out of oldest first, up to the last one sealed when the pass started
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cursor {
+    pub(crate) segment: u64, // segment being copied out of
+    pub(crate) offset: u64,  // offset of its next record
+    pub(crate) records: u64, // records of it passed so far
+    pub(crate) last: u64,
+}
+
+// Commands read by `read_chunk` at their offsets, and where the next read carries on
+type Chunk = (Vec<(u64, Commands)>, Option<u64>);
+
+// Read the commands of a segment from `offset` on, along with the offsets of their
+// frames, until the read passes `budget` bytes. Also returns the offset the next
+// read carries on at, `None` once the end of the segment was reached. `record` is
+// the index of the record at `offset`, to report a damaged one.
+pub(crate) fn read_chunk(
+    storage: &Storage,
+    path: &Path,
+    segment: u64,
+    codec: &Codec,
+    offset: u64,
+    record: u64,
+    budget: u64,
+) -> Result<Chunk> {
+    let mut reader = BufReader::new(storage.open(path)?);
+    reader.seek(SeekFrom::Start(offset))?;
+    let (mut commands, mut next) = (Vec::new(), offset);
+    while next - offset < budget {
+        let payload = match format::read_frame(&mut reader, segment, next)? {
+            Some(payload) => payload,
+            None => return Ok((commands, None)),
+        };
+        let read = record + commands.len() as u64;
+        let command = codec
+            .decode(&payload)
+            .map_err(|e| replay_failed(segment, next as usize, read, e))?;
+        commands.push((next, command));
+        next += (PREFIX_LEN + payload.len()) as u64;
+    }
+    Ok((commands, Some(next)))
+}
+
+// Frames sampled from the oldest segment by `estimate_records`
+const SAMPLED_FRAMES: usize = 16;
+
+// Rough number of records across the segments, from their sizes and the average
+// length of the first few frames of the oldest segment. That one is usually written
+// by a compaction, so the estimate comes close to the number of live keys.
+pub(crate) fn estimate_records(segments: &Segments) -> Result<usize> {
+    let ids = segments.ids()?;
+    let oldest = match ids.first() {
+        Some(&oldest) => oldest,
+        None => return Ok(0),
+    };
+    let mut size = 0;
+    for &id in &ids {
+        size += segments
+            .storage
+            .len(&segments.path(id))?
+            .saturating_sub(HEADER_LEN as u64);
+    }
+    let mut reader = BufReader::new(segments.storage.open(&segments.path(oldest))?);
+    reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    // only the prefixes are read, the replay reports whatever is wrong with the segment
+    let (mut sampled, mut len) = (0, 0);
+    let mut prefix = [0; PREFIX_LEN];
+    while sampled < SAMPLED_FRAMES && reader.read_exact(&mut prefix).is_ok() {
+        let mut payload_len = [0; 4];
+        payload_len.copy_from_slice(&prefix[..4]);
+        let payload_len = u32::from_le_bytes(payload_len);
+        reader.seek_relative(payload_len as i64)?;
+        sampled += 1;
+        len += (PREFIX_LEN as u64) + payload_len as u64;
+    }
+    if sampled == 0 {
+        return Ok(0);
+    }
+    Ok((size / (len / sampled as u64).max(1)) as usize)
+}
+
+// What to do with a frame cut short at the end of a segment, as left by a crash
+// part way through an append
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Tail {
+    // fail the replay
+    Strict,
+    // truncate it away
+    Truncate,
+    // stop the replay before it, leaving the segment untouched
+    Keep,
+}
+
+// Record the position of every frame in a segment into the index,
+// returning the length of the segment and the number of records in it.
+// A transaction left uncommitted at the end is ignored, and truncated away along
+// with a torn tail.
+pub(crate) fn index_segment(
+    storage: &Storage,
+    path: &Path,
+    id: u64,
+    codec: Codec,
+    map: &mut Index,
+    tail: Tail,
+) -> Result<(u64, u64)> {
+    let mut reader = BufReader::new(storage.open(path)?);
+    reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut start = HEADER_LEN;
+    let mut records = 0;
+    let mut group = Group::default();
+    // records of the segment before the open transaction
+    let mut before_group = 0;
+    loop {
+        let payload = match format::read_frame(&mut reader, id, start as u64) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(KvsError::IoError(e))
+                if tail == Tail::Keep && e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                log::warn!(
+                    "ignoring incomplete record at offset {} of log segment {}",
+                    start,
+                    id
+                );
+                break;
+            }
+            Err(KvsError::IoError(e))
+                if tail == Tail::Truncate && e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                log::warn!(
+                    "truncating incomplete record at offset {} of log segment {}",
+                    start,
+                    id
+                );
+                storage.open_write(path)?.set_len(start as u64)?;
+                break;
+            }
+            // already says where it is
+            Err(e @ KvsError::ChecksumMismatch { .. }) => return Err(e),
+            Err(e) => return Err(replay_failed(id, start, records, e)),
+        };
+        let len = PREFIX_LEN + payload.len();
+        let command: Commands = codec
+            .decode(&payload)
+            .map_err(|e| replay_failed(id, start, records, e))?;
+        let position = Position {
+            segment: id,
+            start,
+            len,
+            value_len: command.value_len(),
+            expires: command.expires(),
+        };
+        if let Commands::Begin(_) = command {
+            before_group = records;
+        }
+        if let Some((command, position)) = group.push(command, position) {
+            index_command(map, command, position);
+        }
+        for (command, position) in group.committed() {
+            index_command(map, command, position);
+        }
+        start += len;
+        records += 1;
+    }
+    if let Some(begin) = group.uncommitted() {
+        log::warn!(
+            "ignoring uncommitted transaction at offset {} of log segment {}",
+            begin.start,
+            id
+        );
+        if tail == Tail::Truncate {
+            storage.open_write(path)?.set_len(begin.start as u64)?;
+            return Ok((begin.start as u64, before_group));
+        }
+    }
+    Ok((start as u64, records))
+}
+
+fn index_command(map: &mut Index, command: Commands, position: Position) {
+    match command.into_parts() {
+        (k, Some(_)) => {
+            map.insert(k, position);
+        }
+        (k, None) => {
+            map.remove(&k);
+        }
+    }
+}
+
+// Compacted segment being written by `Wal::rewrite`
+struct Rewrite {
+    segment: u64,
+    temp: PathBuf,
+    codec: Codec,
+    writer: BufWriter<Handle>,
+    map: Index,
+    offset: usize, // where the next record goes
+}
+
+impl Rewrite {
+    // Write the frame of a `Set` of the key and index it
+    fn push(
+        &mut self,
+        key: Vec<u8>,
+        frame: &[u8],
+        value_len: usize,
+        expires: Option<u64>,
+    ) -> Result<()> {
+        self.writer.write_all(frame)?;
+        self.map.insert(
+            key,
+            Position {
+                segment: self.segment,
+                start: self.offset,
+                len: frame.len(),
+                value_len,
+                expires,
+            },
+        );
+        self.offset += frame.len();
+        Ok(())
+    }
+}
+
+// Contents of a hint file, the index into a compacted segment of the given length
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    segment_len: u64,
+    entries: Vec<HintEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HintEntry {
+    key: Vec<u8>,
+    start: u64,
+    len: u64,
+    value_len: u64,
+    expires: Option<u64>,
+}
+
+// Write the index into a compacted segment next to it, so that opening the log can
+// load it rather than replay the segment. The hint is a single bincode frame, written
+// to a temporary file renamed into place once complete.
+fn write_hint(segments: &Segments, id: u64, segment_len: u64, index: &Index) -> Result<()> {
+    let hint = Hint {
+        segment_len,
+        entries: index
+            .iter()
+            .map(|(key, position)| HintEntry {
+                key: key.clone(),
+                start: position.start as u64,
+                len: position.len as u64,
+                value_len: position.value_len as u64,
+                expires: position.expires,
+            })
+            .collect(),
+    };
+    let path = segments.hint(id);
+    let temp = path.with_extension("hint.tmp");
+    let mut file = segments.storage.create(&temp)?;
+    file.write_all(&format::frame(&bincode::serialize(&hint)?))?;
+    file.sync_all()?;
+    segments.storage.rename(&temp, &path)?;
+    Ok(())
+}
+
+// The keys of a segment along with their positions, as listed by its hint, and the
+// length of the segment. `None` without a hint, or with one that doesn't match the
+// segment, which then has to be replayed.
+pub(crate) fn read_hint(segments: &Segments, id: u64) -> Result<Option<(u64, Index)>> {
+    let mut reader = match segments.storage.open(&segments.hint(id)) {
+        Ok(file) => BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint: Hint = match format::read_frame(&mut reader, id, 0) {
+        Ok(Some(payload)) => match bincode::deserialize(&payload) {
+            Ok(hint) => hint,
+            Err(e) => return Ok(stale(id, &e)),
+        },
+        Ok(None) => return Ok(stale(id, &"empty")),
+        Err(e) => return Ok(stale(id, &e)),
+    };
+    // appended to since it was compacted
+    let segment_len = segments.storage.len(&segments.path(id))?;
+    if hint.segment_len != segment_len {
+        return Ok(stale(id, &"segment has changed"));
+    }
+    let index = hint
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let position = Position {
+                segment: id,
+                start: entry.start as usize,
+                len: entry.len as usize,
+                value_len: entry.value_len as usize,
+                expires: entry.expires,
+            };
+            (entry.key, position)
+        })
+        .collect();
+    Ok(Some((segment_len, index)))
+}
+
+fn stale(id: u64, reason: &dyn fmt::Display) -> Option<(u64, Index)> {
+    log::warn!("ignoring the hint of log segment {}: {}", id, reason);
+    None
+}
+
+fn replay_failed(segment: u64, offset: usize, record: u64, e: KvsError) -> KvsError {
+    KvsError::ReplayFailed {
+        segment,
+        offset: offset as u64,
+        record,
+        source: Box::new(e),
+    }
+}
+
+// A value along with its expiry, if it has one
+pub(crate) type Value = (Vec<u8>, Option<u64>);
+
+// Latest value of every live key
+pub(crate) type Live = HashMap<Vec<u8>, Value>;
+
+// The commands that took effect, those of a transaction only if it was committed,
+// without the markers of the transactions
+pub(crate) fn committed(commands: Vec<Commands>) -> Vec<Commands> {
+    let mut out = Vec::with_capacity(commands.len());
+    let mut group = Group::default();
+    for command in commands {
+        if let Some((command, ())) = group.push(command, ()) {
+            out.push(command);
+        }
+        out.extend(group.committed().map(|(command, ())| command));
+    }
+    out
+}
+
+// Transaction being replayed, its records are held back until its commit marker is
+// read. A transaction without one, or with too few records before it, never took
+// effect.
+#[derive(Debug)]
+pub(crate) struct Group<T> {
+    // records it is made of and where the `Begin` marker is, while one is open
+    open: Option<(u64, T)>,
+    held: Vec<(Commands, T)>,
+    done: Vec<(Commands, T)>,
+}
+
+impl<T> Default for Group<T> {
+    fn default() -> Self {
+        Group {
+            open: None,
+            held: Vec::new(),
+            done: Vec::new(),
+        }
+    }
+}
+
+impl<T> Group<T> {
+    // Replay a command found at `at`, returned back if it takes effect right away
+    pub(crate) fn push(&mut self, command: Commands, at: T) -> Option<(Commands, T)> {
+        match command {
+            Commands::Begin(records) => {
+                if self.open.is_some() {
+                    log::warn!("dropping a transaction that was never committed");
+                }
+                self.held.clear();
+                self.open = Some((records, at));
+                None
+            }
+            Commands::Commit => {
+                match self.open.take() {
+                    Some((records, _)) if records == self.held.len() as u64 => {
+                        self.done.append(&mut self.held);
+                    }
+                    _ => {
+                        log::warn!("dropping a transaction that doesn't match its commit");
+                        self.held.clear();
+                    }
+                }
+                None
+            }
+            command if self.open.is_some() => {
+                self.held.push((command, at));
+                None
+            }
+            command => Some((command, at)),
+        }
+    }
+
+    // Records of the transaction just committed, if one was
+    pub(crate) fn committed(&mut self) -> impl Iterator<Item = (Commands, T)> + '_ {
+        self.done.drain(..)
+    }
+
+    // Where the transaction left open at the end of the replay begins, its records
+    // are dropped
+    pub(crate) fn uncommitted(&mut self) -> Option<T> {
+        self.held.clear();
+        self.open.take().map(|(_, at)| at)
+    }
+}
+
+// Replay commands into the latest value of every live key,
+// dropping values that have expired by `now`
+pub(crate) fn live_values(commands: Vec<Commands>, now: u64) -> Live {
+    let mut mapping: Live = HashMap::new();
+    for c in committed(commands) {
+        match c.into_parts() {
+            (k, Some(value)) => {
+                mapping.insert(k, value);
+            }
+            (k, None) => {
+                mapping.remove(&k);
+            }
+        }
+    }
+    mapping.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+    mapping
+}
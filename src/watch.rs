@@ -0,0 +1,47 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// A change to a key of a `KvStore`, as received from `KvStore::subscribe`.
+///
+/// Keys are given as bytes, a key set through the string methods converts back
+/// with `String::from_utf8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// The key was set to a new value.
+    Set {
+        /// Key that was set
+        key: Vec<u8>,
+    },
+    /// The key was removed, or expired.
+    Remove {
+        /// Key that was removed
+        key: Vec<u8>,
+    },
+}
+
+// Events a subscriber can fall behind by before further ones are dropped for it
+pub(crate) const SUBSCRIBER_CAPACITY: usize = 1024;
+
+// Senders of the receivers handed out by `KvStore::subscribe`
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers(Vec<SyncSender<ChangeEvent>>);
+
+impl Subscribers {
+    pub(crate) fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.0.push(sender);
+        receiver
+    }
+
+    // Send an event to every subscriber without waiting on any, one whose receiver
+    // was dropped is forgotten
+    pub(crate) fn notify(&mut self, event: impl Fn() -> ChangeEvent) {
+        self.0.retain(|sender| match sender.try_send(event()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(event)) => {
+                log::warn!("dropping {:?} for a subscriber that fell behind", event);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
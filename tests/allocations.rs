@@ -0,0 +1,112 @@
+use kvs::{KvStore, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+// Counts allocations, the bytes allocated at any one time and the most there ever
+// were. Tests hold `SERIAL` while they measure, so only one runs at a time.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static SERIAL: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(now, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const VALUE_LEN: usize = 256 * 1024;
+
+fn value(key_id: usize, round: u8) -> String {
+    let fill = (b'a' + (key_id % 26) as u8) as char;
+    format!("{}:{}", round, fill.to_string().repeat(VALUE_LEN))
+}
+
+// Compaction copies the live records one at a time, so it should never hold more
+// than a few values in memory however large the live data is.
+#[test]
+fn compaction_streams_live_records() -> Result<()> {
+    let _serial = SERIAL.lock().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let keys = 40;
+    for round in 0..2 {
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id), value(key_id, round))?;
+        }
+    }
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    store.compact()?;
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    let live = keys * VALUE_LEN;
+    assert!(
+        peak < 4 * VALUE_LEN,
+        "compaction allocated {} bytes at once for {} bytes of live values",
+        peak,
+        live
+    );
+
+    for key_id in 0..keys {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 1)));
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..keys {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 1)));
+    }
+    Ok(())
+}
+
+// Overwriting a key with owned strings should allocate for its encoded record only:
+// the payload and its frame. The key and value move into the log without a copy.
+#[test]
+fn set_moves_owned_strings() -> Result<()> {
+    let _serial = SERIAL.lock().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let writes = 1000;
+    let entries: Vec<_> = (0..writes)
+        .map(|n| (format!("key{}", n % 10), format!("value{}", n)))
+        .collect();
+    // every key is in the index before counting, so no write adds an entry
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "")?;
+    }
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for (key, value) in entries {
+        store.set(key, value)?;
+    }
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+    assert!(
+        allocations <= 2 * writes + writes / 10,
+        "{} allocations for {} writes",
+        allocations,
+        writes
+    );
+
+    store.set("key0", "borrowed")?;
+    assert_eq!(store.get("key0")?, Some("borrowed".to_owned()));
+    assert_eq!(store.get("key1")?, Some(format!("value{}", writes - 9)));
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::Write;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for the server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for the server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +200,68 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for the server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// `kvs-server --engine memory` should start in a directory holding another engine's data.
+#[test]
+fn server_cli_memory_ignores_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for the server");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for the server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +269,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +277,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +285,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +293,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +301,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +309,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +317,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +330,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for the server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +368,437 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-server` should shut down cleanly on SIGTERM, keeping the acknowledged writes.
+#[cfg(unix)]
+#[test]
+fn server_cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let status = child.wait().expect("unable to wait for the server");
+    assert!(status.success(), "server exited with {}", status);
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for the server");
+}
+
+// `kvs-client` should fail with a message when no server is listening.
+#[test]
+fn client_cli_unreachable_server() {
+    let temp_dir = TempDir::new().unwrap();
+    // a port that was free a moment ago
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("IO error"));
+}
+
+// `kvs export` should write a file that `kvs import` loads into another directory.
+#[test]
+fn cli_export_import() {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let snapshot = target.path().join("snapshot.json");
+    for (k, v) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", k, v])
+            .current_dir(&source)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("export")
+        .arg(&snapshot)
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        fs::read_to_string(&snapshot).unwrap(),
+        r#"{"key1":"value1","key2":"value2"}"#
+    );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "stale"])
+        .current_dir(&target)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("import")
+        .arg(&snapshot)
+        .current_dir(&target)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&target)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs dump` should print each record with its offset, and how far it got
+// before a damaged tail.
+#[test]
+fn cli_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in [["set", "key1", "value1"], ["set", "key2", "value2"]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let records = "1:5 SET key1 value1\n1:43 SET key2 value2\n1:81 RM key1\n";
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("dump")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(records);
+
+    // half of a record prefix
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))
+        .unwrap();
+    log.write_all(&[1, 2, 3]).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("dump")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(format!(
+            "{}1:105 error: IO error: unexpected end of file\n",
+            records
+        ));
+}
+
+// `kvs --path <DIR>` should use the data in that directory rather than the current one.
+#[test]
+fn cli_path_overrides_current_dir() {
+    let data = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--path"])
+        .arg(data.path())
+        .current_dir(&elsewhere)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("--path")
+        .arg(data.path())
+        .args(["get", "key1"])
+        .current_dir(&elsewhere)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    assert_eq!(fs::read_dir(elsewhere.path()).unwrap().count(), 0);
+
+    // without it the current directory is used
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&data)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `KVS_PATH` and `KVS_ENGINE` should stand in for their flags, which still win.
+#[test]
+fn cli_env_defaults() {
+    let data = TempDir::new().unwrap();
+    let flagged = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("KVS_PATH", data.path())
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&elsewhere)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--engine", "sled"])
+        .current_dir(&data)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    assert_eq!(fs::read_dir(elsewhere.path()).unwrap().count(), 0);
+
+    // the flags take precedence over the variables
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2", "--engine", "kvs", "--path"])
+        .arg(flagged.path())
+        .env("KVS_PATH", data.path())
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&elsewhere)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "--engine", "kvs"])
+        .current_dir(&flagged)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .env("KVS_PATH", data.path())
+        .current_dir(&elsewhere)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    // an engine from the environment is checked against the data like the flag
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&flagged)
+        .assert()
+        .failure();
+}
+
+// `KVS_THRESHOLD` should set when the log is compacted, unless `--threshold` is given.
+#[test]
+fn cli_env_threshold() {
+    // records of key1 left in the log after setting it three times
+    let records = |env: Option<&str>, flag: Option<&str>| {
+        let temp_dir = TempDir::new().unwrap();
+        for value in ["value1", "value2", "value3"] {
+            let mut cmd = Command::cargo_bin("kvs").unwrap();
+            cmd.args(["set", "key1", value]).current_dir(&temp_dir);
+            if let Some(threshold) = env {
+                cmd.env("KVS_THRESHOLD", threshold);
+            }
+            if let Some(threshold) = flag {
+                cmd.args(["--threshold", threshold]);
+            }
+            cmd.assert().success();
+        }
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("dump")
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains(" SET key1 "))
+            .count()
+    };
+    assert_eq!(records(None, None), 3);
+    // every write takes the log past one byte, so only the last value is kept
+    assert_eq!(records(Some("1"), None), 1);
+    assert_eq!(records(Some("1"), Some("1000000")), 3);
+    assert_eq!(records(None, Some("1")), 1);
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("KVS_THRESHOLD", "lots")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs` should print results to stdout, errors to stderr, and exit 0 only on success.
+#[test]
+fn cli_output_and_exit_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd.assert()
+    };
+
+    kvs(&["set", "key1", "value1"])
+        .code(0)
+        .stdout(is_empty())
+        .stderr(is_empty());
+    kvs(&["get", "key1"])
+        .code(0)
+        .stdout("value1\n")
+        .stderr(is_empty());
+    // a missing key is an answer rather than a failure
+    kvs(&["get", "key2"])
+        .code(0)
+        .stdout("Key not found\n")
+        .stderr(is_empty());
+    kvs(&["rm", "key1"])
+        .code(0)
+        .stdout(is_empty())
+        .stderr(is_empty());
+    kvs(&["rm", "key1"])
+        .code(2)
+        .stdout(is_empty())
+        .stderr("Key not found\n");
+    kvs(&[])
+        .code(1)
+        .stdout(is_empty())
+        .stderr("No command specified\n");
+
+    let snapshot = temp_dir.path().join("snapshot.json");
+    let snapshot = snapshot.to_str().unwrap();
+    kvs(&["export", snapshot])
+        .code(0)
+        .stdout(is_empty())
+        .stderr(is_empty());
+    kvs(&["import", snapshot])
+        .code(0)
+        .stdout(is_empty())
+        .stderr(is_empty());
+    let missing = temp_dir.path().join("missing").join("snapshot.json");
+    let missing = missing.to_str().unwrap();
+    kvs(&["export", missing])
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("IO error"));
+    kvs(&["import", missing])
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("IO error"));
+    kvs(&["dump"]).code(0).stderr(is_empty());
+
+    // usage errors are reported by the argument parser
+    kvs(&["get"])
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Usage"));
+}
+
+// `kvs get --default` should print the default in place of a missing key only.
+#[test]
+fn cli_get_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd.assert()
+    };
+
+    kvs(&["set", "key1", "value1"]).success();
+    kvs(&["get", "key1", "--default", "fallback"])
+        .code(0)
+        .stdout("value1\n")
+        .stderr(is_empty());
+    kvs(&["get", "key2", "--default", "fallback"])
+        .code(0)
+        .stdout("fallback\n")
+        .stderr(is_empty());
+    kvs(&["get", "key2", "--default", ""])
+        .code(0)
+        .stdout("\n")
+        .stderr(is_empty());
+    kvs(&["get", "key2"])
+        .code(0)
+        .stdout("Key not found\n")
+        .stderr(is_empty());
+    kvs(&["get", "key2", "--default", "fallback", "--format", "json"])
+        .success()
+        .stdout("{\"found\":false,\"key\":\"key2\",\"value\":\"fallback\"}\n");
+    kvs(&["get", "key1", "--default", "fallback", "--format", "json"])
+        .success()
+        .stdout("{\"found\":true,\"key\":\"key1\",\"value\":\"value1\"}\n");
+}
+
+// `kvs --format json` should print one JSON object per command.
+#[test]
+fn cli_json_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd.assert()
+    };
+
+    kvs(&["set", "key1", "value \"1\"", "--format", "json"])
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value \\\"1\\\"\"}\n");
+    kvs(&["--format", "json", "get", "key1"])
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value \\\"1\\\"\"}\n");
+    kvs(&["get", "key2", "--format", "json"])
+        .success()
+        .stdout("{\"key\":\"key2\",\"value\":null}\n");
+    kvs(&["rm", "key1", "--format", "json"])
+        .success()
+        .stdout("{\"key\":\"key1\",\"removed\":true}\n");
+    // errors stay on stderr
+    kvs(&["rm", "key1", "--format", "json"])
+        .code(2)
+        .stdout(is_empty())
+        .stderr("Key not found\n");
+
+    // the text format is the default
+    kvs(&["set", "key1", "value1"]).success().stdout(is_empty());
+    kvs(&["get", "key1", "--format", "text"])
+        .success()
+        .stdout("value1\n");
+    kvs(&["get", "key2"]).success().stdout("Key not found\n");
+}
@@ -0,0 +1,201 @@
+use kvs::{
+    existing_engine, resolve_engine, InMemoryKvsEngine, KvStore, KvsEngine, KvsError, Result,
+    SledKvsEngine,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// Opens an engine of a given implementation in a directory
+type Open = fn(&Path) -> Result<Box<dyn KvsEngine>>;
+
+fn open_kvs(path: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(KvStore::open(path)?))
+}
+
+fn open_sled(path: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(SledKvsEngine::open(path)?))
+}
+
+thread_local! {
+    static MEMORY: RefCell<HashMap<PathBuf, InMemoryKvsEngine>> = RefCell::new(HashMap::new());
+}
+
+// Reopening a directory hands out another handle to the engine first opened there,
+// standing in for the data a disk engine would find
+fn open_memory(path: &Path) -> Result<Box<dyn KvsEngine>> {
+    let engine = MEMORY.with(|engines| {
+        engines
+            .borrow_mut()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone()
+    });
+    Ok(Box::new(engine))
+}
+
+// Should get previously stored value, also after reopening
+fn get_stored_value(open: Open) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+fn overwrite_value(open: Open) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+fn get_non_existent_value(open: Open) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should remove a key and refuse to remove a missing one
+fn remove_key(open: Open) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+macro_rules! engine_tests {
+    ($engine:ident, $open:expr) => {
+        mod $engine {
+            use super::*;
+
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                super::get_stored_value($open)
+            }
+
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                super::overwrite_value($open)
+            }
+
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                super::get_non_existent_value($open)
+            }
+
+            #[test]
+            fn remove_key() -> Result<()> {
+                super::remove_key($open)
+            }
+        }
+    };
+}
+
+engine_tests!(kvs_engine, open_kvs);
+engine_tests!(sled_engine, open_sled);
+engine_tests!(memory_engine, open_memory);
+
+// A directory should only ever be opened by the engine that first used it.
+#[test]
+fn engines_reject_each_others_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    match SledKvsEngine::open(temp_dir.path()) {
+        Err(KvsError::WrongEngine(engine)) => assert_eq!(engine, "kvs"),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    KvStore::open(temp_dir.path())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::WrongEngine(engine)) => assert_eq!(engine, "sled"),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// The engine of a directory should be found from its marker, or from the files of
+// one written before the marker existed.
+#[test]
+fn existing_engine_is_recognized() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(existing_engine(temp_dir.path()), None);
+    KvStore::open(temp_dir.path())?;
+    assert_eq!(existing_engine(temp_dir.path()), Some("kvs".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(existing_engine(temp_dir.path()), Some("sled".to_owned()));
+    fs::remove_file(temp_dir.path().join("engine"))?;
+    assert_eq!(existing_engine(temp_dir.path()), Some("sled".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("log.txt"), "")?;
+    assert_eq!(existing_engine(temp_dir.path()), Some("kvs".to_owned()));
+
+    Ok(())
+}
+
+// The engine to open a directory with should follow the request, then its data.
+#[test]
+fn resolve_engine_checks_the_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(resolve_engine(temp_dir.path(), None)?, "kvs");
+    assert_eq!(resolve_engine(temp_dir.path(), Some("sled"))?, "sled");
+
+    SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(resolve_engine(temp_dir.path(), None)?, "sled");
+    assert_eq!(resolve_engine(temp_dir.path(), Some("sled"))?, "sled");
+    assert!(matches!(
+        resolve_engine(temp_dir.path(), Some("kvs")),
+        Err(KvsError::WrongEngine(engine)) if engine == "sled"
+    ));
+
+    // a marker of an engine unknown here is a mismatch all the same
+    fs::write(temp_dir.path().join("engine"), "other")?;
+    assert!(matches!(
+        resolve_engine(temp_dir.path(), Some("sled")),
+        Err(KvsError::WrongEngine(engine)) if engine == "other"
+    ));
+
+    Ok(())
+}
@@ -1,7 +1,68 @@
-use kvs::{KvStore, KvsEngine, Result};
+// These tests pass owned keys on purpose, borrowed ones are covered by get_borrows_key
+#![allow(clippy::unnecessary_to_owned)]
+
+use kvs::{
+    ChangeEvent, Clock, CompactionMode, KvStats, KvStore, KvStoreConfig, KvsEngine, KvsError,
+    LogFormat, LogRecord, RepairMode, RepairReport, Result, SyncMode,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Segment files of the log, oldest first
+fn segments(dir: &Path) -> Vec<PathBuf> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .filter_map(|entry| {
+            let path = entry.expect("unable to read directory entry").path();
+            match path.extension() {
+                Some(ext) if ext == "log" => path.file_stem()?.to_str()?.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect();
+    ids.sort_unstable();
+    ids.iter()
+        .map(|id| dir.join(format!("{}.log", id)))
+        .collect()
+}
+
+// Combined size of every segment of the log
+fn log_size(dir: &Path) -> u64 {
+    segments(dir)
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .expect("unable to read log metadata")
+                .len()
+        })
+        .sum()
+}
+
+// Clock that only moves when told to
+#[derive(Debug, Default)]
+struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -124,3 +185,3117 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Index should point into the compacted log without reopening the store.
+#[test]
+fn get_after_compaction_without_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let padding = "x".repeat(4096);
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            let value = format!("value{}-{}{}", key_id, iter, padding);
+            store.set(key, value)?;
+        }
+    }
+
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(
+            store.get(key)?,
+            Some(format!("value{}-2{}", key_id, padding))
+        );
+    }
+
+    Ok(())
+}
+
+// Log should contain every serialized command in full.
+#[test]
+fn log_length_matches_written_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().format(LogFormat::Json);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    let large = "x".repeat(1 << 20);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    // a header per segment, then a length and checksum prefix per record
+    let expected: usize = 5 * segments(temp_dir.path()).len()
+        + [("large", large.as_str()), ("small", "value")]
+            .iter()
+            .map(|(k, v)| 8 + serde_json::json!({ "Set": [k, v] }).to_string().len())
+            .sum::<usize>();
+    assert_eq!(log_size(temp_dir.path()) as usize, expected);
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+
+    Ok(())
+}
+
+// Should return an error instead of panicking on a corrupt log.
+#[test]
+fn corrupt_log_returns_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+
+    // record overwritten in place after the index was built
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = fs::metadata(&log)?.len() as usize;
+    fs::write(&log, "#".repeat(len))?;
+    assert!(store.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// A handful of large records should be enough to trigger compaction.
+#[test]
+fn compaction_measures_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "x".repeat(512 * 1024);
+    for _ in 0..3 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+
+    let len = log_size(temp_dir.path());
+    assert!(len < 2 * value.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should compact according to a user supplied threshold.
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(Some(1024));
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+
+    let len = log_size(temp_dir.path());
+    assert!(len <= 1024);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Should never compact when automatic compaction is disabled.
+#[test]
+fn disabled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    let value = "x".repeat(512 * 1024);
+    for _ in 0..3 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+
+    let len = log_size(temp_dir.path());
+    assert!(len > 3 * value.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Explicit compaction should shrink the log and keep live keys readable.
+#[test]
+fn explicit_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    let log_len = || log_size(temp_dir.path());
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..900 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let before = log_len();
+    let reclaimed = store.compact()?;
+    assert!(reclaimed > 0);
+    assert_eq!(log_len(), before - reclaimed);
+    for key_id in 900..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // already compact
+    assert_eq!(store.compact()?, 0);
+    assert_eq!(store.get("key950".to_owned())?, Some("value950".to_owned()));
+
+    Ok(())
+}
+
+// Many sequential sets should all be readable before and after reopening.
+#[test]
+fn many_sequential_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(
+        store.get("key9999".to_owned())?,
+        Some("value9999".to_owned())
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should be usable purely through the engine trait.
+#[test]
+fn usable_as_engine() -> Result<()> {
+    fn exercise(engine: &mut dyn KvsEngine) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.remove("key1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        assert!(engine.remove("key1".to_owned()).is_err());
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    exercise(&mut store)
+}
+
+// Cloned handles should read concurrently while another handle writes.
+#[test]
+fn concurrent_readers_and_writer() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync>() {}
+    assert_shareable::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(Some(4096));
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let mut reader = store.clone();
+            s.spawn(move || {
+                for iter in 0..1000 {
+                    let value = reader
+                        .get(format!("key{}", iter % 10))
+                        .expect("unable to read value")
+                        .expect("key should always be present");
+                    assert!(value.parse::<u32>().unwrap() < 100);
+                }
+            });
+        }
+
+        let mut writer = store.clone();
+        s.spawn(move || {
+            for iter in 0..100 {
+                for key_id in 0..10 {
+                    writer
+                        .set(format!("key{}", key_id), iter.to_string())
+                        .expect("unable to write value");
+                }
+            }
+        });
+    });
+
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Store should own its path, so it can outlive it and move to other threads.
+#[test]
+fn store_owns_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().to_path_buf();
+    let mut store = KvStore::open(&path)?;
+    drop(path);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let handle = thread::spawn(move || store.get("key1".to_owned()));
+    let value = handle.join().expect("reader thread panicked")?;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Every sync mode should persist acknowledged writes.
+#[test]
+fn sync_modes_persist_writes() -> Result<()> {
+    for sync in [SyncMode::Always, SyncMode::Never, SyncMode::EveryN(3)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig::new().sync(sync);
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Every format should round trip, and logs in another format are migrated.
+#[test]
+fn log_formats_round_trip() -> Result<()> {
+    let formats = [LogFormat::Json, LogFormat::Bincode];
+    for (first, second) in [(formats[0], formats[1]), (formats[1], formats[0])] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().format(first))?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().format(second))?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        store.set("key3".to_owned(), "value3".to_owned())?;
+
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().format(second))?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A log of bare json records, from before the log header, should be migrated.
+#[test]
+fn legacy_json_log_is_migrated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("log.txt");
+    fs::write(
+        &log,
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Rm":"key1"}"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!log.exists());
+    for segment in segments(temp_dir.path()) {
+        assert!(fs::read(segment)?.starts_with(b"KVS"));
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A damaged length prefix should surface as an error, not a bogus value.
+#[test]
+fn corrupt_length_prefix_returns_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    // a record per segment, so that the damaged one is not at the tail of the log
+    let config = KvStoreConfig::new().segment_size(16);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the first record's prefix directly follows the 5 byte header
+    let mut content = fs::read(&log)?;
+    content[5..9].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log, &content)?;
+
+    assert!(store.get("key1".to_owned()).is_err());
+    drop(store);
+    assert!(KvStore::open_with(temp_dir.path(), config).is_err());
+
+    Ok(())
+}
+
+// A compaction that fails part way should leave the existing log intact.
+#[test]
+fn failed_compaction_keeps_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    // occupy the temporary file of the compacted segment so that it can't be written
+    fs::create_dir(temp_dir.path().join("2.log.compact"))?;
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    fs::remove_dir(temp_dir.path().join("2.log.compact"))?;
+    assert!(store.compact()? > 0);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Appends should roll over to new segments once the active one is full.
+#[test]
+fn segments_roll_over() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None).segment_size(1024);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let files = segments(temp_dir.path());
+    assert!(files.len() > 1);
+    // only the active segment may have grown past the limit by more than a record
+    for segment in &files[..files.len() - 1] {
+        assert!(fs::metadata(segment)?.len() < 1024 + 64);
+    }
+
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should replace the old segments with a single fresh one.
+#[test]
+fn compaction_deletes_old_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None).segment_size(1024);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let old = segments(temp_dir.path());
+    assert!(old.len() > 1);
+
+    assert!(store.compact()? > 0);
+    for segment in &old {
+        assert!(!segment.exists());
+    }
+    // the compacted segment and a fresh one for new appends
+    assert_eq!(segments(temp_dir.path()).len(), 2);
+
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-9", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Keys should list exactly the live keys.
+#[test]
+fn keys_lists_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys().is_empty());
+
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key1".to_owned(), "overwritten".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let mut keys = store.keys();
+    keys.sort();
+    assert_eq!(keys, vec!["key0", "key1", "key2", "key4"]);
+
+    Ok(())
+}
+
+// Len should count live keys only.
+#[test]
+fn len_counts_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.len(), 2);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.len(), 2);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len(), 1);
+    assert!(!store.is_empty());
+    store.remove("key2".to_owned())?;
+    assert!(store.is_empty());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 0);
+
+    Ok(())
+}
+
+// Contains_key should agree with get.
+#[test]
+fn contains_key_matches_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key2"));
+    assert!(!store.contains_key("key3"));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Iter should yield every surviving pair with its latest value.
+#[test]
+fn iter_yields_live_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut expected = Vec::new();
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..20 {
+        if key_id % 3 == 0 {
+            store.remove(format!("key{}", key_id))?;
+        } else if key_id % 3 == 1 {
+            store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+            expected.push((format!("key{}", key_id), format!("new{}", key_id)));
+        } else {
+            expected.push((format!("key{}", key_id), format!("value{}", key_id)));
+        }
+    }
+
+    let mut pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
+
+// Insert should hand back the value it replaced.
+#[test]
+fn insert_returns_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.insert("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.insert("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.insert("key1".to_owned(), "value3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch of sets should read back like individual ones.
+#[test]
+fn set_many_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().segment_size(4096);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set_many(
+        (0..1000)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+            .chain(std::iter::once(("key1".to_owned(), "last".to_owned()))),
+    )?;
+    store.set_many(Vec::new())?;
+    assert_eq!(store.len(), 1000);
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+        for key_id in (0..1000).filter(|&key_id| key_id != 1) {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Get_many should answer in the order the keys were asked for.
+#[test]
+fn get_many_keeps_request_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().segment_size(256);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    store.set("key3".to_owned(), "newer".to_owned())?;
+
+    let keys: Vec<String> = ["key40", "missing", "key3", "key7", "key0", "key40"]
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+    assert_eq!(
+        store.get_many(&keys)?,
+        vec![
+            Some("value40".to_owned()),
+            None,
+            Some("newer".to_owned()),
+            None,
+            Some("value0".to_owned()),
+            Some("value40".to_owned()),
+        ]
+    );
+    assert!(store.get_many(&[])?.is_empty());
+
+    Ok(())
+}
+
+// Keys set with a time to live should vanish once it has passed.
+#[test]
+fn ttl_expires_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let config = KvStoreConfig::new().clock(clock.clone());
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+
+    store.set("permanent".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    store.set_with_ttl(
+        "renewed".to_owned(),
+        "old".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set("renewed".to_owned(), "new".to_owned())?;
+
+    clock.advance(9);
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len(), 4);
+
+    clock.advance(1);
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(!store.contains_key("short"));
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("renewed".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("permanent".to_owned())?, Some("value".to_owned()));
+
+    // expiry survives reopening, expired or not
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    clock.advance(90);
+    assert_eq!(store.get("long".to_owned())?, None);
+    assert_eq!(store.get("permanent".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Removing a key whose time to live has passed should fail as for a missing key.
+#[test]
+fn remove_expired_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let config = KvStoreConfig::new().clock(clock.clone());
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(10),
+    )?;
+
+    clock.advance(10);
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction should drop expired keys and keep the expiry of the others.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let config = KvStoreConfig::new().threshold(None).clock(clock.clone());
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    let value = "x".repeat(1000);
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            value.clone(),
+            Duration::from_secs(5),
+        )?;
+    }
+    store.set_with_ttl("later".to_owned(), value.clone(), Duration::from_secs(50))?;
+
+    clock.advance(5);
+    assert!(store.compact()? > 10 * value.len() as u64);
+    assert_eq!(store.keys(), vec!["later"]);
+    assert_eq!(store.get("later".to_owned())?, Some(value));
+
+    clock.advance(45);
+    assert_eq!(store.get("later".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compare_and_swap should only write when the current value matches.
+#[test]
+fn compare_and_swap_checks_current_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    // absent key
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value".to_owned()),
+        "a".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, "a".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("a".to_owned()));
+
+    // mismatch
+    assert!(!store.compare_and_swap("key1".to_owned(), None, "b".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), Some("b".to_owned()), "c".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("a".to_owned()));
+
+    // match
+    assert!(store.compare_and_swap("key1".to_owned(), Some("a".to_owned()), "b".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("b".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("b".to_owned()));
+
+    Ok(())
+}
+
+// The replay should list every write in order, tombstones included.
+#[test]
+fn log_records_replay_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None).segment_size(64);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let records = store.log_records()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        records,
+        vec![
+            LogRecord::Set("key1".to_owned(), "value1".to_owned()),
+            LogRecord::Set("key2".to_owned(), "value2".to_owned()),
+            LogRecord::Remove("key1".to_owned()),
+            LogRecord::Set("key1".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    // the history starts over from the compacted records
+    store.compact()?;
+    let mut records = store.log_records()?.collect::<Result<Vec<_>>>()?;
+    records.sort_by_key(|record| format!("{:?}", record));
+    assert_eq!(
+        records,
+        vec![
+            LogRecord::Set("key1".to_owned(), "value3".to_owned()),
+            LogRecord::Set("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// A flipped bit should be reported as a checksum mismatch at its record.
+#[test]
+fn flipped_byte_fails_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let second = fs::metadata(&log)?.len();
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // last byte of the payload of the final record
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 1;
+    fs::write(&log, &content)?;
+
+    match store.get("key3".to_owned()) {
+        Err(KvsError::ReadFailed { key, source, .. }) => {
+            assert_eq!(key, "key3");
+            assert!(matches!(
+                *source,
+                KvsError::ChecksumMismatch { segment: 1, offset } if offset == second
+            ));
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ChecksumMismatch { segment: 1, offset }) if offset == second
+    ));
+
+    Ok(())
+}
+
+// Segments written before records carried a checksum should be migrated.
+#[test]
+fn unchecked_segment_is_migrated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut content = b"KVS\x01\x00".to_vec();
+    for record in [
+        r#"{"Set":["key1","value1"]}"#,
+        r#"{"Set":["key2","value2"]}"#,
+    ] {
+        content.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        content.extend_from_slice(record.as_bytes());
+    }
+    fs::write(temp_dir.path().join("1.log"), content)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    for segment in segments(temp_dir.path()) {
+        assert_eq!(fs::read(segment)?[3], 2);
+    }
+
+    Ok(())
+}
+
+// A record cut short at the tail of the log should be dropped on open.
+#[test]
+fn truncated_tail_is_recovered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let complete = fs::metadata(&log)?.len();
+
+    // half-written command at the tail of the log
+    let mut f = OpenOptions::new().append(true).open(&log)?;
+    f.write_all(&[20, 0, 0, 0, 1, 2, 3, 4, 5])?;
+    drop(f);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), complete);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // appends carry on from the last complete record
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // a prefix cut short is dropped too
+    let mut f = OpenOptions::new().append(true).open(&log)?;
+    f.write_all(&[20, 0])?;
+    drop(f);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    Ok(())
+}
+
+// Readers should keep finding every key while compaction replaces the segments
+// underneath them.
+#[test]
+fn reads_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None).segment_size(256);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    thread::scope(|scope| -> Result<()> {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut store = store.clone();
+                scope.spawn(move || -> Result<()> {
+                    for _ in 0..20 {
+                        for key_id in 0..100 {
+                            assert_eq!(
+                                store.get(format!("key{}", key_id))?,
+                                Some(format!("value{}", key_id))
+                            );
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            store.compact()?;
+        }
+        for reader in readers {
+            reader.join().expect("reader thread panicked")?;
+        }
+        Ok(())
+    })
+}
+
+// A compacted segment should be compressed when asked for and read back the same,
+// including after reopening.
+#[test]
+fn compressed_segment_round_trips() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "value".repeat(200);
+    let config = KvStoreConfig::new().threshold(None).compress(true);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{}{}", value, key_id))?;
+    }
+    let uncompressed = log_size(temp_dir.path());
+    store.compact()?;
+
+    // the compacted segment is flagged and smaller, the new active one is not
+    let segments = segments(temp_dir.path());
+    let compacted = fs::read(&segments[0])?;
+    assert_eq!(compacted[4] & 0x80, 0x80);
+    assert_eq!(fs::read(&segments[1])?[4] & 0x80, 0);
+    assert!((compacted.len() as u64) < uncompressed / 10);
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, key_id))
+        );
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.get("key42".to_owned())?, Some(format!("{}42", value)));
+    assert_eq!(store.log_records()?.count(), 100);
+
+    Ok(())
+}
+
+// Turning compression off should keep compressed segments readable and compact
+// into an uncompressed one.
+#[test]
+fn compression_can_be_turned_off() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone().compress(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    let segments = segments(temp_dir.path());
+    assert_eq!(segments.len(), 2);
+    assert_eq!(fs::read(&segments[0])?[4] & 0x80, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Stores with different names in the same directory should not see each other's
+// keys, across compaction and reopening.
+#[test]
+fn named_stores_share_a_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let users = KvStoreConfig::new().name("users");
+    let orders = KvStoreConfig::new().name("orders");
+    let mut store1 = KvStore::open_with(temp_dir.path(), users.clone())?;
+    let mut store2 = KvStore::open_with(temp_dir.path(), orders.clone())?;
+    let mut store3 = KvStore::open(temp_dir.path())?;
+
+    store1.set("key1".to_owned(), "user".to_owned())?;
+    store2.set("key1".to_owned(), "order".to_owned())?;
+    store2.set("key2".to_owned(), "order".to_owned())?;
+    store1.compact()?;
+    assert_eq!(store1.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store2.get("key1".to_owned())?, Some("order".to_owned()));
+    assert_eq!(store3.get("key1".to_owned())?, None);
+    drop(store1);
+    drop(store2);
+
+    assert!(temp_dir.path().join("users.2.log").exists());
+    assert!(temp_dir.path().join("orders.1.log").exists());
+    let store1 = KvStore::open_with(temp_dir.path(), users)?;
+    let store2 = KvStore::open_with(temp_dir.path(), orders)?;
+    assert_eq!(store1.keys(), vec!["key1".to_owned()]);
+    assert_eq!(store2.len(), 2);
+    assert!(store3.is_empty());
+
+    Ok(())
+}
+
+// Pairs exported from one store should import into another, overwriting what
+// is already there.
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().name("source"))?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    let mut snapshot = Vec::new();
+    store.export(&mut snapshot)?;
+
+    let mut fresh = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().name("target"))?;
+    fresh.set("key1".to_owned(), "stale".to_owned())?;
+    fresh.set("extra".to_owned(), "kept".to_owned())?;
+    fresh.import(snapshot.as_slice())?;
+
+    for key_id in 0..50 {
+        assert_eq!(
+            fresh.get(format!("key{}", key_id))?,
+            store.get(format!("key{}", key_id))?
+        );
+    }
+    assert_eq!(fresh.get("extra".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(fresh.len(), 50);
+
+    Ok(())
+}
+
+// A set key should always decode as a set, and a record in the slot of the old
+// `Get` command should be reported as damaged rather than read as a missing value.
+#[test]
+fn set_never_decodes_as_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let records = store.log_records()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        records,
+        vec![LogRecord::Set("key1".to_owned(), "value1".to_owned())]
+    );
+    drop(store);
+
+    // bincode variant 2 followed by the key
+    let mut payload = 2u32.to_le_bytes().to_vec();
+    payload.extend_from_slice(&4u64.to_le_bytes());
+    payload.extend_from_slice(b"key1");
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(&segments(temp_dir.path())[0])?;
+    log.write_all(&(payload.len() as u32).to_le_bytes())?;
+    log.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    log.write_all(&payload)?;
+    drop(log);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ReplayFailed { source, .. }) => {
+            assert!(matches!(*source, KvsError::BincodeError(_)))
+        }
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A tombstone that can't be written should fail the remove and leave the key in
+// place. Every append rolls over to a new segment, which a read-only directory
+// refuses.
+#[cfg(unix)]
+#[test]
+fn failed_remove_keeps_key() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().segment_size(1);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o555))?;
+    // permissions aren't enforced for root
+    let probe = temp_dir.path().join("probe");
+    if fs::File::create(&probe).is_ok() {
+        fs::remove_file(probe)?;
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+        return Ok(());
+    }
+    let result = store.remove("key1".to_owned());
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+
+    assert!(matches!(result, Err(KvsError::IoError(_))));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // the store carries on once the directory is writable again
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Appends should land where the index says they do after removes, reopens and a
+// torn tail being truncated away.
+#[test]
+fn offsets_follow_file_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        if key_id % 3 == 0 {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+    drop(store);
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(&segments(temp_dir.path())[0])?;
+    log.write_all(&[7; 6])?;
+    drop(log);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 20..40 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..40 {
+            let expected = match key_id {
+                0..20 if key_id % 3 == 0 => None,
+                _ => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)?;
+    assert_eq!(KvStore::open(temp_dir.path())?.log_records()?.count(), 47);
+
+    Ok(())
+}
+
+// Flushed writes should be found after the store is dropped and reopened.
+#[test]
+fn flush_persists_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().sync(SyncMode::Never);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    store.set_many((0..10).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))))?;
+    store.remove("key3".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.len(), 9);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A store opened at a file path should keep its log next to the path, apart from
+// a store opened on the directory.
+#[test]
+fn open_file_at_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data.db");
+    let mut store = KvStore::open_file(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(temp_dir.path().join("data.db.1.log").exists());
+    let mut store = KvStore::open_file(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut other = KvStore::open(temp_dir.path())?;
+    assert_eq!(other.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Dead bytes should rise with overwrites and drop to the segment headers once
+// compacted.
+#[test]
+fn stats_track_dead_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let fresh = store.stats();
+    assert_eq!(fresh.live_keys, 10);
+    assert_eq!(fresh.records, 10);
+    assert_eq!(fresh.log_size, log_size(temp_dir.path()));
+    assert_eq!(fresh.compactions, 0);
+
+    for iter in 0..5 {
+        for key_id in 0..10 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let overwritten = store.stats();
+    assert_eq!(overwritten.live_keys, 9);
+    assert_eq!(overwritten.records, 61);
+    assert!(overwritten.dead_bytes > fresh.dead_bytes * 5);
+
+    store.compact()?;
+    let compacted = store.stats();
+    assert_eq!(compacted.live_keys, 9);
+    assert_eq!(compacted.records, 9);
+    assert_eq!(compacted.compactions, 1);
+    // a header for the compacted segment and the one appended to now
+    assert_eq!(compacted.dead_bytes, 10);
+    assert_eq!(compacted.log_size, log_size(temp_dir.path()));
+    drop(store);
+
+    // the counts are rebuilt on open
+    let reopened = KvStore::open_with(temp_dir.path(), config)?.stats();
+    assert_eq!(
+        reopened,
+        KvStats {
+            compactions: 0,
+            #[cfg(feature = "metrics")]
+            timings: Default::default(),
+            ..compacted
+        }
+    );
+
+    Ok(())
+}
+
+// A value that can't be read should be reported along with its key and where its
+// record is.
+#[test]
+fn read_error_names_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = log_size(temp_dir.path());
+    store.set("broken".to_owned(), "value2".to_owned())?;
+
+    let log = &segments(temp_dir.path())[0];
+    let mut content = fs::read(log)?;
+    let last = content.len() - 1;
+    content[last] ^= 1;
+    fs::write(log, &content)?;
+
+    let message = store
+        .get("broken".to_owned())
+        .expect_err("read a damaged record")
+        .to_string();
+    assert!(message.contains("broken"), "{}", message);
+    assert!(
+        message.contains(&format!("offset {}", offset)),
+        "{}",
+        message
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A large value should stream out of the log byte for byte, in every format.
+#[test]
+fn get_reader_streams_value() -> Result<()> {
+    use std::io::Read;
+
+    let value: String = (0..4 * 1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    for config in [
+        KvStoreConfig::new(),
+        KvStoreConfig::new().format(LogFormat::Json),
+        KvStoreConfig::new().compress(true),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        store.set("big".to_owned(), value.clone())?;
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "short".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.compact()?;
+
+        let mut streamed = Vec::new();
+        let mut reader = store.get_reader("big")?.expect("big is set");
+        reader.read_to_end(&mut streamed)?;
+        assert_eq!(streamed.len(), value.len());
+        assert!(streamed == value.as_bytes());
+
+        let mut short = String::new();
+        store
+            .get_reader("ttl")?
+            .expect("ttl is set")
+            .read_to_string(&mut short)?;
+        assert_eq!(short, "short");
+        assert!(store.get_reader("missing")?.is_none());
+    }
+
+    Ok(())
+}
+
+// A streamed value whose record was damaged should fail once read to its end.
+#[test]
+fn get_reader_checks_checksum() -> Result<()> {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = &segments(temp_dir.path())[0];
+    let mut content = fs::read(log)?;
+    let last = content.len() - 1;
+    content[last] ^= 1;
+    fs::write(log, &content)?;
+
+    let mut value = Vec::new();
+    let result = store
+        .get_reader("key1")?
+        .expect("key1 is set")
+        .read_to_end(&mut value);
+    assert_eq!(
+        result.map_err(|e| e.kind()),
+        Err(std::io::ErrorKind::InvalidData)
+    );
+
+    Ok(())
+}
+
+// Reader handing out a few bytes at a time, splitting characters between reads
+struct Trickle<'a>(&'a [u8]);
+
+impl std::io::Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.0.len()).min(7);
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+// A large value set from a reader should read back whole, in either format and
+// across reopening.
+#[test]
+fn set_from_reader_round_trips() -> Result<()> {
+    use std::io::Read;
+
+    let value = "héllo wörld ☃ 𝄞 ".repeat(100_000);
+    for config in [
+        KvStoreConfig::new(),
+        KvStoreConfig::new().format(LogFormat::Json),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+        store.set("before".to_owned(), "value".to_owned())?;
+        store.set_from_reader("big".to_owned(), value.as_bytes())?;
+        store.set_from_reader("trickle".to_owned(), Trickle(value.as_bytes()))?;
+        store.set("after".to_owned(), "value".to_owned())?;
+
+        let mut streamed = Vec::new();
+        store
+            .get_reader("big")?
+            .expect("big is set")
+            .read_to_end(&mut streamed)?;
+        assert_eq!(streamed.len(), value.len());
+        assert!(store.get("trickle".to_owned())? == Some(value.clone()));
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        assert!(store.get("big".to_owned())? == Some(value.clone()));
+        assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("after".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A value that isn't UTF-8 should be refused without leaving anything in the log.
+#[test]
+fn set_from_reader_rejects_invalid_utf8() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let size = log_size(temp_dir.path());
+
+    let mut bad = "value".repeat(20_000).into_bytes();
+    bad.push(0xff);
+    assert!(store
+        .set_from_reader("key1".to_owned(), bad.as_slice())
+        .is_err());
+    // a character cut short at the end of the value
+    assert!(store
+        .set_from_reader("key1".to_owned(), "☃".as_bytes()[..2].as_ref())
+        .is_err());
+    assert_eq!(log_size(temp_dir.path()), size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set_from_reader("key2".to_owned(), "value2".as_bytes())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// With deferred compaction writes past the threshold should only raise the hint,
+// until the caller compacts.
+#[test]
+fn deferred_compaction_waits_for_caller() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new()
+        .threshold(Some(1024))
+        .compaction(CompactionMode::Deferred);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert!(!store.should_compact());
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key1".to_owned())?;
+    store.set_many((0..10).map(|key_id| (format!("key{}", key_id), "value".to_owned())))?;
+
+    let stats = store.stats();
+    assert!(store.should_compact());
+    assert!(stats.needs_compaction);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.log_size, log_size(temp_dir.path()));
+    assert!(stats.log_size > 1024);
+
+    store.compact()?;
+    assert!(!store.should_compact());
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.len(), 10);
+
+    Ok(())
+}
+
+// A log should only be open in one store at a time, until that store is dropped.
+#[test]
+fn second_open_is_locked_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked)
+    ));
+    // clones share the lock, other logs in the directory have their own
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked)
+    ));
+    KvStore::open_with(temp_dir.path(), KvStoreConfig::new().name("other"))?;
+    drop(clone);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The value should only be computed when the key is missing.
+#[test]
+fn get_or_insert_with_computes_on_miss() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = store.get_or_insert_with("key1".to_owned(), || "value1".to_owned())?;
+    assert_eq!(value, "value1");
+    let value = store.get_or_insert_with("key1".to_owned(), || panic!("key1 is present"))?;
+    assert_eq!(value, "value1");
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Removing an absent key should report it rather than fail.
+#[test]
+fn remove_if_present_reports_removal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().clock(clock.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(store.remove_if_present("key1".to_owned())?);
+    assert!(!store.remove_if_present("key1".to_owned())?);
+    assert!(!store.remove_if_present("key2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // nothing is written for an absent key
+    let records = store.log_records()?.count();
+    assert!(!store.remove_if_present("key1".to_owned())?);
+    assert_eq!(store.log_records()?.count(), records);
+
+    // nor was an expired key there to remove
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    clock.advance(10);
+    assert!(!store.remove_if_present("key3".to_owned())?);
+    assert!(!store.contains_key("key3"));
+
+    Ok(())
+}
+
+// Counters should start from zero and persist their running total.
+#[test]
+fn increment_counts_from_zero() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("count".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("count".to_owned(), 2)?, 7);
+    assert_eq!(store.decrement("count".to_owned(), 10)?, -3);
+
+    store.set("other".to_owned(), "40".to_owned())?;
+    assert_eq!(store.increment("other".to_owned(), 2)?, 42);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("count".to_owned())?, Some("-3".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("42".to_owned()));
+
+    Ok(())
+}
+
+// Appending should extend an existing value after the separator, start a fresh key
+// with the suffix alone, and persist the result.
+#[test]
+fn append_value_extends_lists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("list", "a")?;
+    store.append_value("list".to_owned(), "b", ",")?;
+    store.append_value("list".to_owned(), "c", ",")?;
+    assert_eq!(store.get("list")?, Some("a,b,c".to_owned()));
+
+    store.append_value("lines".to_owned(), "first", "\n")?;
+    assert_eq!(store.get("lines")?, Some("first".to_owned()));
+    store.append_value("lines".to_owned(), "second", "\n")?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list")?, Some("a,b,c".to_owned()));
+    assert_eq!(store.get("lines")?, Some("first\nsecond".to_owned()));
+
+    // appends through other handles all land
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let mut store = store.clone();
+            thread::spawn(move || {
+                store.append_value("shared".to_owned(), &thread_id.to_string(), ",")
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let shared = store.get("shared")?.unwrap();
+    let mut items: Vec<_> = shared.split(',').collect();
+    items.sort_unstable();
+    assert_eq!(items, ["0", "1", "2", "3"]);
+
+    Ok(())
+}
+
+// A value that isn't an integer should be left alone.
+#[test]
+fn increment_rejects_non_integers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.increment("key1".to_owned(), 1),
+        Err(KvsError::NotAnInteger(key)) if key == "key1"
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow(_))
+    ));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    Ok(())
+}
+
+// A read-only store should serve reads, reject writes and leave the log alone.
+#[test]
+fn read_only_rejects_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreConfig::new().threshold(Some(1)))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let size = log_size(temp_dir.path());
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    // several readers can share the log, but not with a writer
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked)
+    ));
+    drop(other);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(!store.should_compact());
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(log_size(temp_dir.path()), size);
+
+    Ok(())
+}
+
+// Opening an empty directory read-only should find an empty store, not start a log.
+#[test]
+fn read_only_empty_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.is_empty());
+    assert!(segments(temp_dir.path()).is_empty());
+
+    Ok(())
+}
+
+// Walking the keys a page at a time should visit each key once, in order.
+#[test]
+fn keys_paged_covers_every_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..95 {
+        store.set(format!("key{:03}", i), "value".to_owned())?;
+    }
+    store.remove("key050".to_owned())?;
+
+    let mut seen = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let page = store.keys_paged(after.as_deref(), 10);
+        assert!(page.len() <= 10);
+        match page.last() {
+            Some(last) => after = Some(last.clone()),
+            None => break,
+        }
+        seen.extend(page);
+    }
+    let expected: Vec<String> = (0..95)
+        .filter(|i| *i != 50)
+        .map(|i| format!("key{:03}", i))
+        .collect();
+    assert_eq!(seen, expected);
+
+    // the cursor doesn't have to be a key of the store
+    assert_eq!(
+        store.keys_paged(Some("key050"), 2),
+        vec!["key051", "key052"]
+    );
+    assert!(store.keys_paged(None, 0).is_empty());
+
+    Ok(())
+}
+
+// A range scan should yield the keys inside its bounds in sorted order.
+#[test]
+fn range_scans_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["m", "c", "a", "z", "k", "b", "n"] {
+        store.set(key.to_owned(), format!("value-{}", key))?;
+    }
+    store.remove("k".to_owned())?;
+
+    let pairs = store
+        .range("a".to_owned().."m".to_owned())
+        .collect::<Result<Vec<_>>>()?;
+    let expected: Vec<(String, String)> = ["a", "b", "c"]
+        .iter()
+        .map(|key| (key.to_string(), format!("value-{}", key)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    let keys = store
+        .range("m".to_owned()..)
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["m", "n", "z"]);
+    assert_eq!(store.range(..).count(), 6);
+
+    Ok(())
+}
+
+// Only keys starting with the prefix should come back, in sorted order.
+#[test]
+fn prefix_scan_matches_prefix_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in [
+        "user:12:name",
+        "user:123:name",
+        "user:123:email",
+        "user:1234:name",
+        "user:2:name",
+        "user:",
+        "users",
+    ] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+
+    let keys = |prefix: &str| -> Result<Vec<String>> {
+        store
+            .prefix_scan(prefix)
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    };
+    assert_eq!(keys("user:123:")?, vec!["user:123:email", "user:123:name"]);
+    assert_eq!(
+        keys("user:123")?,
+        vec!["user:1234:name", "user:123:email", "user:123:name"]
+    );
+    assert_eq!(
+        keys("user:")?,
+        vec![
+            "user:",
+            "user:1234:name",
+            "user:123:email",
+            "user:123:name",
+            "user:12:name",
+            "user:2:name"
+        ]
+    );
+    assert!(keys("user:3")?.is_empty());
+    assert_eq!(keys("")?.len(), 7);
+
+    let pairs = store.prefix_scan("user:2").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![("user:2:name".to_owned(), "USER:2:NAME".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Every position rebuilt on open should bound exactly the record of its key, whatever
+// the format and however the values are spread over segments.
+#[test]
+fn reopened_positions_round_trip() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = || {
+            KvStoreConfig::new()
+                .format(format)
+                .segment_size(256)
+                .threshold(None)
+        };
+        let mut store = KvStore::open_with(temp_dir.path(), config())?;
+        let mut expected = HashMap::new();
+        for i in 0..200 {
+            let key = format!("key {}\n", i % 70);
+            let value = format!("{} ü \"{}\" {}", i, " ".repeat(i % 13), "\n".repeat(i % 3));
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+        store.remove("key 3\n".to_owned())?;
+        expected.remove("key 3\n");
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), config())?;
+        assert_eq!(store.len(), expected.len());
+        for (key, value) in &expected {
+            assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+        }
+        store.compact()?;
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), config())?;
+        for (key, value) in &expected {
+            assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+        }
+    }
+
+    Ok(())
+}
+
+// A cleared store should stay empty across a reopen and keep taking writes.
+#[test]
+fn clear_empties_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || KvStoreConfig::new().segment_size(128).threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let size = log_size(temp_dir.path());
+
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(log_size(temp_dir.path()) < size);
+    assert_eq!(store.stats().live_keys, 0);
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    assert_eq!(store.keys(), vec!["key1"]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Writes handed to the background writer should read back right away and be on
+// disk once flushed.
+#[test]
+fn background_writer_persists_on_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        KvStoreConfig::new()
+            .background_writer(true)
+            .segment_size(4096)
+            .threshold(Some(64 * 1024))
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    for i in 0..2000 {
+        let key = format!("key{}", i % 500);
+        store.set(key.clone(), format!("value{}", i))?;
+        assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+    }
+    store.remove("key1".to_owned())?;
+    let mut reader = store.get_reader("key2")?.expect("key2 is set");
+    let mut value = String::new();
+    reader.read_to_string(&mut value)?;
+    assert_eq!(value, "value1502");
+
+    store.flush()?;
+    assert_eq!(log_size(temp_dir.path()), store.stats().log_size);
+    assert_eq!(store.log_records()?.count() as u64, store.stats().records);
+
+    // dropping the store writes out what is still queued
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    assert_eq!(store.len(), 500);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    for i in 1500..2000 {
+        if i % 500 != 1 {
+            let key = format!("key{}", i % 500);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// With a dead ratio, only a log mostly made of dead records should be compacted.
+#[test]
+fn dead_ratio_gates_compaction() -> Result<()> {
+    let config = || {
+        KvStoreConfig::new()
+            .threshold(Some(4 * 1024))
+            .dead_ratio(Some(0.5))
+    };
+
+    // overwriting one key leaves nearly all of the log dead
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(log_size(temp_dir.path()) < 8 * 1024);
+
+    // distinct keys are all live
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), config())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.log_size > 4 * 1024);
+    assert!(!stats.needs_compaction);
+
+    // until enough of them are removed, also after reopening
+    drop(store);
+    let mut store = KvStore::open_with(
+        temp_dir.path(),
+        config().compaction(CompactionMode::Deferred),
+    )?;
+    for i in 0..600 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert!(store.should_compact());
+    store.compact()?;
+    assert_eq!(store.len(), 400);
+    assert!(!store.should_compact());
+
+    Ok(())
+}
+
+// A store should open from any kind of path.
+#[test]
+fn open_accepts_any_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path: PathBuf = temp_dir.path().join("data");
+    let string: String = path
+        .to_str()
+        .expect("temporary path is not UTF-8")
+        .to_owned();
+
+    let mut store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(string.as_str())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let mut store = KvStore::open(string)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    // the store doesn't borrow the path it was opened with
+    let mut store = KvStore::open(path.clone())?;
+    drop(path);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Reads and removals should take borrowed keys, no clone needed.
+#[test]
+fn get_borrows_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key = "key1".to_owned();
+    store.set(key.clone(), "value1".to_owned())?;
+
+    assert_eq!(store.get(&key)?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert!(store.contains_key(&key));
+    store.remove(&key)?;
+    assert!(matches!(store.remove("key1"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get(key)?, None);
+
+    Ok(())
+}
+
+// Keys and values that aren't UTF-8 should read back intact, through a reopen and a
+// compaction, in either log format.
+#[test]
+fn bytes_round_trip() -> Result<()> {
+    for format in [LogFormat::Bincode, LogFormat::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig::new().format(format);
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+        let key = vec![0xff, 0x00, 0xfe];
+        let value = vec![0x80, 0x81, 0x00, 0xc3];
+        store.set_bytes(key.clone(), value.clone())?;
+        store.set_bytes(b"key1".to_vec(), vec![0xc0, 0xc1])?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+        assert_eq!(store.get_bytes(b"key2")?, Some(b"value2".to_vec()));
+        assert!(matches!(store.get("key1"), Err(KvsError::Utf8Error(_))));
+        // only the UTF-8 keys have a string to list them by
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["key1".to_owned(), "key2".to_owned()]);
+        assert_eq!(store.len(), 3);
+
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+        assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+        store.compact()?;
+        assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+        let mut read = Vec::new();
+        store
+            .get_reader("key1")?
+            .expect("key1 is set")
+            .read_to_end(&mut read)?;
+        assert_eq!(read, vec![0xc0, 0xc1]);
+
+        store.remove_bytes(&key)?;
+        assert_eq!(store.get_bytes(&key)?, None);
+        assert!(matches!(
+            store.remove_bytes(&key),
+            Err(KvsError::KeyNotFound)
+        ));
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        assert_eq!(store.get_bytes(&key)?, None);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A capacity hint should only size the index, whatever the hint the store reopens
+// with the same contents.
+#[test]
+fn capacity_hint_keeps_contents() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0")?;
+    drop(store);
+
+    for capacity in [0, 10, 1_000_000] {
+        let config = KvStoreConfig::new().capacity(capacity);
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        assert_eq!(store.len(), 999);
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key999")?, Some("value999".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A `for` loop over a borrowed store should visit every live pair, a damaged record
+// showing up as an error item.
+#[test]
+fn for_loop_over_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3")?;
+
+    let mut pairs = Vec::new();
+    for entry in &store {
+        pairs.push(entry?);
+    }
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    // flip a byte of the last record of key2
+    let log = &segments(temp_dir.path())[0];
+    let mut content = fs::read(log)?;
+    let second = content
+        .windows(6)
+        .rposition(|window| window == b"value2")
+        .expect("value2 is in the log");
+    content[second] ^= 1;
+    fs::write(log, &content)?;
+
+    let mut errors = 0;
+    for entry in &store {
+        match entry {
+            Ok(pair) => assert_eq!(pair, ("key1".to_owned(), "value1".to_owned())),
+            Err(_) => errors += 1,
+        }
+    }
+    assert_eq!(errors, 1);
+
+    Ok(())
+}
+
+// Merging should copy every live key of the other store, its values winning where
+// both have a key, and leave the other store as it was.
+#[test]
+fn merge_copies_other_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut other = KvStore::open(other_dir.path())?;
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 1000..3000 {
+        other.set(format!("key{}", key_id), format!("other{}", key_id))?;
+    }
+    other.remove("key2999")?;
+
+    store.merge(&other)?;
+    assert_eq!(store.len(), 2999);
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1000")?, Some("other1000".to_owned()));
+    assert_eq!(store.get("key2998")?, Some("other2998".to_owned()));
+    assert_eq!(store.get("key2999")?, None);
+    assert_eq!(other.len(), 1999);
+    assert_eq!(other.get("key0")?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2999);
+    assert_eq!(store.get("key1500")?, Some("other1500".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot taken while other handles read and compact should open to the contents
+// the store had when it was taken.
+#[test]
+fn snapshot_under_concurrent_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let dest = snapshot_dir.path().join("snapshot");
+    thread::scope(|scope| -> Result<()> {
+        for _ in 0..4 {
+            let mut reader = store.clone();
+            scope.spawn(move || {
+                for key_id in 100..1000 {
+                    assert_eq!(
+                        reader.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            });
+        }
+        store.clone().snapshot(&dest)
+    })?;
+    // the snapshot doesn't follow writes made after it
+    store.set("key500".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+
+    let mut snapshot = KvStore::open(&dest)?;
+    assert_eq!(snapshot.len(), 900);
+    assert_eq!(snapshot.get("key0")?, None);
+    assert_eq!(snapshot.get("key500")?, Some("value500".to_owned()));
+    assert_eq!(snapshot.get("key999")?, Some("value999".to_owned()));
+    assert_eq!(snapshot.stats().records, 900);
+    // a directory that already holds something is refused
+    assert!(store.snapshot(&dest).is_err());
+
+    Ok(())
+}
+
+// The length kept in the index should match the bytes of each value, however it was
+// written and after a reopen and a compaction.
+#[test]
+fn value_len_counts_value_bytes() -> Result<()> {
+    for format in [LogFormat::Bincode, LogFormat::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig::new().format(format);
+        let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+        let values = [
+            ("key1", "value1".to_owned()),
+            ("key2", "ünïcödé".to_owned()),
+            ("key3", String::new()),
+            ("key4", "x".repeat(10_000)),
+        ];
+        for (key, value) in &values {
+            store.set((*key).to_owned(), value.clone())?;
+        }
+        store.set_with_ttl(
+            "key5".to_owned(),
+            "value5".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.set_from_reader("key6".to_owned(), "streamed".as_bytes())?;
+        store.set_bytes(b"key7".to_vec(), vec![0xff; 3])?;
+
+        let check = |store: &KvStore| {
+            for (key, value) in &values {
+                assert_eq!(store.value_len(key), Some(value.len()));
+            }
+            assert_eq!(store.value_len("key5"), Some(6));
+            assert_eq!(store.value_len("key6"), Some(8));
+            assert_eq!(store.value_len("key7"), Some(3));
+            assert_eq!(store.value_len("missing"), None);
+        };
+        check(&store);
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), config)?;
+        check(&store);
+        store.compact()?;
+        check(&store);
+        store.remove("key1")?;
+        assert_eq!(store.value_len("key1"), None);
+    }
+
+    Ok(())
+}
+
+// Codec writing each field of a record as a length then the bytes, after a tag,
+// counting the records it encodes
+#[derive(Debug, Default)]
+struct Fields {
+    encoded: AtomicU64,
+}
+
+impl Fields {
+    fn field(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    fn fields(mut payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut fields = Vec::new();
+        while payload.len() >= 4 {
+            let len = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+            fields.push(payload[4..4 + len].to_vec());
+            payload = &payload[4 + len..];
+        }
+        fields
+    }
+}
+
+impl kvs::RecordCodec for Fields {
+    fn encode(&self, record: &LogRecord) -> Result<Vec<u8>> {
+        self.encoded.fetch_add(1, Ordering::SeqCst);
+        let mut out = Vec::new();
+        match record {
+            LogRecord::Set(k, v) => {
+                out.push(b'S');
+                Self::field(&mut out, k.as_bytes());
+                Self::field(&mut out, v.as_bytes());
+            }
+            LogRecord::SetWithTtl(k, v, expires) => {
+                out.push(b'T');
+                Self::field(&mut out, k.as_bytes());
+                Self::field(&mut out, v.as_bytes());
+                Self::field(&mut out, &expires.to_le_bytes());
+            }
+            LogRecord::Remove(k) => {
+                out.push(b'R');
+                Self::field(&mut out, k.as_bytes());
+            }
+            _ => unimplemented!("only string records are written here"),
+        }
+        Ok(out)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<LogRecord> {
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec());
+        let fields = Self::fields(&payload[1..]);
+        Ok(match payload[0] {
+            b'S' => LogRecord::Set(text(&fields[0])?, text(&fields[1])?),
+            b'T' => LogRecord::SetWithTtl(
+                text(&fields[0])?,
+                text(&fields[1])?,
+                u64::from_le_bytes(fields[2].as_slice().try_into().unwrap()),
+            ),
+            b'R' => LogRecord::Remove(text(&fields[0])?),
+            tag => return Err(KvsError::UnsupportedLog(format!("unknown tag {}", tag))),
+        })
+    }
+}
+
+// Records should go through a custom codec, which is needed to open the log again.
+#[test]
+fn custom_record_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the existing bincode log is rewritten through the codec
+    let codec = Arc::new(Fields::default());
+    let config = KvStoreConfig::new().codec(codec.clone());
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    assert!(codec.encoded.load(Ordering::SeqCst) > 0);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.remove("key2")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedLog(_))
+    ));
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone().compress(true))?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A handle should only be handed out for a live key, and read back its value.
+#[test]
+fn try_get_cached_hands_out_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.try_get_cached("key1"), None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2")?;
+
+    assert_eq!(store.try_get_cached("key2"), None);
+    let handle = store.try_get_cached("key1").expect("key1 is set");
+    assert_eq!(handle.segment, 1);
+    assert_eq!(handle.value_len, 6);
+    assert_eq!(handle.expires, None);
+    assert_eq!(
+        store.read_handle("key1", handle)?,
+        Some("value1".to_owned())
+    );
+
+    // the handle keeps pointing at the record it was taken for
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.read_handle("key1", handle)?,
+        Some("value1".to_owned())
+    );
+    assert!(store.try_get_cached("key1") != Some(handle));
+    // until a compaction moves the records
+    store.compact()?;
+    assert_eq!(
+        store.read_handle("key1", handle)?,
+        Some("value2".to_owned())
+    );
+    // a handle on another key's record is refused
+    let handle = store.try_get_cached("key1").expect("key1 is set");
+    assert!(store.read_handle("key2", handle).is_err());
+
+    Ok(())
+}
+
+// Keys and values past the configured limits should be refused without touching the
+// log, those at the limit accepted.
+#[test]
+fn size_limits_are_enforced() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new()
+        .max_key_bytes(Some(8))
+        .max_value_bytes(Some(16));
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    store.set("k".repeat(8), "v".repeat(16))?;
+    let size = log_size(temp_dir.path());
+
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLarge {
+            limit: 8,
+            actual: 9
+        })
+    ));
+    assert!(matches!(
+        store.set_bytes(b"key".to_vec(), vec![0; 17]),
+        Err(KvsError::ValueTooLarge {
+            limit: 16,
+            actual: 17
+        })
+    ));
+    assert!(matches!(
+        store.set_many(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "v".repeat(17)),
+        ]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.set_from_reader("key3".to_owned(), "v".repeat(100).as_bytes()),
+        Err(KvsError::ValueTooLarge { limit: 16, .. })
+    ));
+    assert_eq!(log_size(temp_dir.path()), size);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(16)));
+
+    Ok(())
+}
+
+// Hint files written next to compacted segments, oldest first
+fn hints(dir: &Path) -> Vec<PathBuf> {
+    let mut hints: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .map(|entry| entry.expect("unable to read a directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect();
+    hints.sort();
+    hints
+}
+
+// An index loaded from the hint of a compacted segment should match the one a full
+// replay builds, and a hint that doesn't match its segment should be ignored.
+#[test]
+fn hint_file_loads_same_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.compact()?;
+    // records after the compaction are replayed either way
+    store.set("key0".to_owned(), "again".to_owned())?;
+    drop(store);
+    assert_eq!(hints(temp_dir.path()).len(), 1);
+
+    let view = |store: &KvStore| {
+        let mut keys = store.keys();
+        keys.sort();
+        let handles: Vec<_> = keys.iter().map(|key| store.try_get_cached(key)).collect();
+        (keys, handles, store.stats())
+    };
+    let hinted = KvStore::open(temp_dir.path())?;
+    let from_hint = view(&hinted);
+    drop(hinted);
+
+    let hint = hints(temp_dir.path()).remove(0);
+    let contents = fs::read(&hint)?;
+    fs::remove_file(&hint)?;
+    let mut replayed = KvStore::open(temp_dir.path())?;
+    assert_eq!(view(&replayed), from_hint);
+    assert_eq!(from_hint.0.len(), 402);
+    assert_eq!(replayed.get("key0")?, Some("again".to_owned()));
+    assert_eq!(replayed.get("key250")?, Some("value250".to_owned()));
+    assert_eq!(replayed.get("ttl")?, Some("value".to_owned()));
+    drop(replayed);
+
+    // a damaged hint falls back to a replay
+    let mut damaged = contents.clone();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 1;
+    fs::write(&hint, damaged)?;
+    assert_eq!(view(&KvStore::open(temp_dir.path())?), from_hint);
+
+    Ok(())
+}
+
+// Repairing a log with a damaged record in the middle should keep the intact records
+// around it, or cut the log short at it, and leave a log that opens again.
+#[test]
+fn repair_drops_damaged_record() -> Result<()> {
+    for &mode in &[RepairMode::Skip, RepairMode::Truncate] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("1.log");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut offsets = Vec::new();
+        for key_id in 0..5 {
+            offsets.push(fs::metadata(&log)?.len());
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        let end = fs::metadata(&log)?.len();
+
+        // last byte of the payload of the third record
+        let mut content = fs::read(&log)?;
+        content[offsets[3] as usize - 1] ^= 1;
+        fs::write(&log, &content)?;
+
+        let report = store.repair(mode)?;
+        let (intact, expected) = match mode {
+            RepairMode::Skip => (
+                vec![0, 1, 3, 4],
+                RepairReport {
+                    scanned: 4,
+                    skipped: 1,
+                    dropped_bytes: offsets[3] - offsets[2],
+                },
+            ),
+            RepairMode::Truncate => (
+                vec![0, 1],
+                RepairReport {
+                    scanned: 2,
+                    skipped: 1,
+                    dropped_bytes: end - offsets[2],
+                },
+            ),
+        };
+        assert_eq!(report, expected);
+        if mode == RepairMode::Truncate {
+            assert_eq!(fs::metadata(&log)?.len(), offsets[2]);
+        }
+
+        let check = |store: &mut KvStore| -> Result<()> {
+            let mut keys = store.keys();
+            keys.sort();
+            let expected: Vec<_> = intact.iter().map(|id| format!("key{}", id)).collect();
+            assert_eq!(keys, expected);
+            for id in &intact {
+                assert_eq!(
+                    store.get(format!("key{}", id))?,
+                    Some(format!("value{}", id))
+                );
+            }
+            assert_eq!(store.stats().live_keys, intact.len() as u64);
+            Ok(())
+        };
+        check(&mut store)?;
+        // nothing is left to repair
+        assert_eq!(store.repair(mode)?.skipped, 0);
+        store.set("key9".to_owned(), "value9".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key9")?, Some("value9".to_owned()));
+        store.remove_if_present("key9")?;
+        check(&mut store)?;
+    }
+
+    Ok(())
+}
+
+// With the `metrics` feature every set, get, remove and compaction should be counted
+// and timed, across all handles of a store.
+#[cfg(feature = "metrics")]
+#[test]
+fn operations_are_timed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let untouched = store.stats().timings;
+    assert_eq!(untouched, Default::default());
+    assert_eq!(untouched.get.average(), Duration::ZERO);
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let mut other = store.clone();
+    for key_id in 0..5 {
+        other.get(format!("key{}", key_id))?;
+    }
+    other.remove("key0")?;
+    // a failed operation isn't counted
+    assert!(store.remove("key0").is_err());
+    store.compact()?;
+
+    let timings = store.stats().timings;
+    assert_eq!(timings.set.count, 10);
+    assert_eq!(timings.get.count, 5);
+    assert_eq!(timings.remove.count, 1);
+    assert_eq!(timings.compact.count, 1);
+    for op in &[timings.set, timings.get, timings.remove, timings.compact] {
+        assert!(op.total > Duration::ZERO);
+        assert!(op.average() > Duration::ZERO);
+        assert!(op.average() <= op.total);
+    }
+
+    Ok(())
+}
+
+// A pass of incremental compaction should take several bounded steps, with every key
+// readable and writable in between, and leave only live records behind.
+#[test]
+fn incremental_compaction_steps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None).segment_size(1024);
+    let mut store = KvStore::open_with(temp_dir.path(), config.clone())?;
+    let mut expected = HashMap::new();
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            let value = format!("value{}-{}", key_id, iter);
+            store.set(format!("key{}", key_id), value.clone())?;
+            expected.insert(format!("key{}", key_id), value);
+        }
+    }
+    for key_id in 0..20 {
+        store.remove(format!("key{}", key_id))?;
+        expected.remove(&format!("key{}", key_id));
+    }
+    let before = log_size(temp_dir.path());
+    let first = segments(temp_dir.path()).len();
+    assert!(first > 5);
+
+    let mut reader = store.clone();
+    let mut steps = 0;
+    while !store.compact_step(512)? {
+        steps += 1;
+        for (key, value) in &expected {
+            assert_eq!(reader.get(key)?.as_ref(), Some(value));
+        }
+        for key_id in 0..20 {
+            assert_eq!(reader.get(format!("key{}", key_id))?, None);
+        }
+        // written while the pass is in progress
+        let key = format!("during{}", steps);
+        store.set(key.clone(), "value".to_owned())?;
+        expected.insert(key, "value".to_owned());
+    }
+    assert!(steps > 5);
+
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.live_keys, expected.len() as u64);
+    assert_eq!(stats.records, expected.len() as u64);
+    assert_eq!(stats.log_size, log_size(temp_dir.path()));
+    assert!(stats.log_size < before / 2);
+    drop((store, reader));
+
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    assert_eq!(store.stats().records, expected.len() as u64);
+    for (key, value) in &expected {
+        assert_eq!(store.get(key)?.as_ref(), Some(value));
+    }
+
+    Ok(())
+}
+
+// With `CompactionMode::Incremental` writes should compact the log a step at a time.
+#[test]
+fn incremental_mode_compacts_on_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new()
+        .threshold(Some(8 * 1024))
+        .segment_size(1024)
+        .compaction(CompactionMode::Incremental(256));
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        assert!(log_size(temp_dir.path()) < 16 * 1024);
+    }
+    assert!(store.stats().compactions > 0);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// The `Debug` output of a store should describe its index and log without showing
+// any value.
+#[test]
+fn debug_output_hides_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().background_writer(true);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    store.set("password".to_owned(), "hunter2-secret".to_owned())?;
+    store.set_bytes(b"token".to_vec(), b"bytes-secret".to_vec())?;
+
+    let debug = format!("{:?}", store);
+    assert!(!debug.contains("hunter2-secret"), "{}", debug);
+    assert!(!debug.contains("bytes-secret"), "{}", debug);
+    assert!(debug.starts_with("KvStore {"), "{}", debug);
+    assert!(debug.contains("keys: 2"), "{}", debug);
+    let pretty = format!("{:#?}", store);
+    assert!(!pretty.contains("hunter2-secret"), "{}", pretty);
+
+    Ok(())
+}
+
+// A store kept in memory should go through sets, reads, removals and compactions
+// the same as one on disk, without touching the filesystem.
+#[test]
+fn in_memory_store_cycle() -> Result<()> {
+    let config = KvStoreConfig::new()
+        .threshold(Some(16 * 1024))
+        .segment_size(1024);
+    let mut store = KvStore::open_in_memory_with(config)?;
+    for iter in 0..50 {
+        for key_id in 0..20 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    assert!(store.stats().compactions > 0);
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-49", key_id))
+        );
+    }
+
+    let mut other = store.clone();
+    for key_id in 0..10 {
+        other.remove(format!("key{}", key_id))?;
+    }
+    assert!(matches!(store.remove("key0"), Err(KvsError::KeyNotFound)));
+    store.set_from_reader("streamed".to_owned(), "x".repeat(5000).as_bytes())?;
+    let mut streamed = String::new();
+    store
+        .get_reader("streamed")?
+        .expect("streamed key is missing")
+        .read_to_string(&mut streamed)?;
+    assert_eq!(streamed, "x".repeat(5000));
+
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 11);
+    assert_eq!(stats.records, 11);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 10..20 {
+        assert_eq!(
+            other.get(format!("key{}", key_id))?,
+            Some(format!("value{}-49", key_id))
+        );
+    }
+    while !store.compact_step(256)? {}
+    assert_eq!(store.stats().live_keys, 11);
+    assert_eq!(store.keys().len(), 11);
+
+    // every store in memory has a log of its own
+    let mut fresh = KvStore::open_in_memory()?;
+    assert_eq!(fresh.get("key10")?, None);
+
+    Ok(())
+}
+
+// Subscribers should receive an event for every key set or removed, in order, and a
+// subscriber that falls behind should miss events rather than hold up writes.
+#[test]
+fn subscribers_receive_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), "value".to_owned())?;
+    let events = store.subscribe();
+    let lagging = store.subscribe();
+    drop(store.subscribe());
+
+    let set = |key: &str| ChangeEvent::Set {
+        key: key.as_bytes().to_vec(),
+    };
+    let removed = |key: &str| ChangeEvent::Remove {
+        key: key.as_bytes().to_vec(),
+    };
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.clone().set("key2".to_owned(), "value2".to_owned())?;
+    store.set_bytes(b"key3".to_vec(), b"value3".to_vec())?;
+    store.remove("key1")?;
+    // failed writes send nothing
+    assert!(store.remove("key1").is_err());
+    store.set_many(vec![("key4".to_owned(), "value4".to_owned())])?;
+    store.compact()?;
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            set("key1"),
+            set("key2"),
+            set("key3"),
+            removed("key1"),
+            set("key4")
+        ]
+    );
+
+    for key_id in 0..2000 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), "value".to_owned())?;
+        assert_eq!(events.try_recv().ok(), Some(set(&key)));
+    }
+    // the first events it missed were queued, later ones dropped
+    assert_eq!(lagging.try_iter().count(), 1024);
+    store.set("after".to_owned(), "value".to_owned())?;
+    assert_eq!(lagging.try_iter().collect::<Vec<_>>(), vec![set("after")]);
+
+    Ok(())
+}
+
+// A committed transaction should apply every write, and one that fails nothing.
+#[test]
+fn transaction_commits_all_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.transaction();
+    txn.set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    assert_eq!(txn.len(), 3);
+    txn.commit()?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    // a removal of a missing key fails the whole transaction
+    let mut txn = store.transaction();
+    txn.set("key4".to_owned(), "value4".to_owned())
+        .remove("key1".to_owned());
+    assert!(matches!(txn.commit(), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key4")?, None);
+    // a key set earlier in the batch can be removed
+    let mut txn = store.transaction();
+    txn.set("key4".to_owned(), "value4".to_owned())
+        .remove("key4".to_owned());
+    txn.commit()?;
+    assert_eq!(store.get("key4")?, None);
+    // a dropped batch writes nothing
+    store
+        .transaction()
+        .set("key5".to_owned(), "value5".to_owned());
+    assert_eq!(store.get("key5")?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4")?, None);
+    store.compact()?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A transaction cut short before its commit marker should be dropped as a whole on
+// replay, and later writes should land as usual.
+#[test]
+fn uncommitted_transaction_is_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    txn.commit()?;
+    drop(store);
+
+    // crash just before the commit marker was written
+    let mut dump = Vec::new();
+    KvStore::dump(temp_dir.path(), &mut dump)?;
+    let dump = String::from_utf8(dump).unwrap();
+    let commit = dump
+        .lines()
+        .find_map(|line| line.strip_suffix(" COMMIT"))
+        .expect("no commit marker in the log");
+    let offset: u64 = commit.split(':').nth(1).unwrap().parse().unwrap();
+    let log = temp_dir.path().join("1.log");
+    OpenOptions::new().write(true).open(&log)?.set_len(offset)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    // the group is truncated away, so it can't swallow later writes
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// An entry should insert a missing key and modify a present one in place, reading
+// the value only when resolved.
+#[test]
+fn entry_inserts_or_modifies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut modified = false;
+    let value = store
+        .entry("key1".to_owned())
+        .and_modify(|_| modified = true)
+        .or_insert("value1".to_owned())?;
+    assert_eq!(value, "value1");
+    assert!(!modified);
+
+    let value = store
+        .entry("key1".to_owned())
+        .and_modify(|value| value.push('a'))
+        .and_modify(|value| value.push('b'))
+        .or_insert_with(|| panic!("the key is present"))?;
+    assert_eq!(value, "value1ab");
+    // without a change the present value is returned as it is
+    assert_eq!(
+        store
+            .entry("key1".to_owned())
+            .or_insert("other".to_owned())?,
+        "value1ab"
+    );
+    assert_eq!(
+        store
+            .entry("key2".to_owned())
+            .or_insert_with(|| "value2".to_owned())?,
+        "value2"
+    );
+    let entry = store.entry("key3".to_owned());
+    assert_eq!(entry.key(), "key3");
+    drop(entry);
+    assert_eq!(store.get("key3")?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1ab".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The reported disk size should match the files of the log, before and after a
+// compaction.
+#[test]
+fn disk_size_matches_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().segment_size(4096).threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    let log_files = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read the store directory")
+            .map(|entry| entry.expect("unable to read directory entry").path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == "log" || ext == "hint")
+            })
+            .map(|path| fs::metadata(path).expect("unable to stat a log file").len())
+            .sum()
+    };
+
+    for iter in 0..5 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+    }
+    let written = store.disk_size()?;
+    assert!(segments(temp_dir.path()).len() > 1);
+    assert_eq!(written, log_files());
+    assert_eq!(written, store.stats().log_size);
+
+    store.compact()?;
+    let compacted = store.disk_size()?;
+    assert!(compacted < written);
+    assert_eq!(compacted, log_files());
+
+    Ok(())
+}
+
+// A record that doesn't parse should be reported along with where it is in the log.
+#[test]
+fn parse_failure_reports_position() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // an intact frame around a payload of an unknown bincode variant
+    let log = segments(temp_dir.path()).remove(0);
+    let bad_offset = fs::metadata(&log)?.len();
+    let payload = 99u32.to_le_bytes();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&(payload.len() as u32).to_le_bytes())?;
+    file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    drop(file);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ReplayFailed {
+            segment,
+            offset,
+            record,
+            ..
+        }) => assert_eq!((segment, offset, record), (1, bad_offset, 2)),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    // a log from before segments reports its offset in the file
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let good = r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}"#;
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        format!("{}{}", good, r#"{"Set":["key3"}"#),
+    )?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ReplayFailed {
+            segment,
+            offset,
+            record,
+            ..
+        }) => assert_eq!((segment, offset, record), (0, good.len() as u64, 2)),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Verify should find the index in line with the log, and report every key the two
+// disagree on once the log changes underneath the store.
+#[test]
+fn verify_reports_divergence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    store.remove("key2")?;
+    store.set("key3", "value4")?;
+    let report = store.verify()?;
+    assert!(report.is_consistent(), "{:?}", report);
+    assert_eq!((report.on_disk, report.indexed), (2, 2));
+    assert_eq!(store.keys_count_on_disk()?, 2);
+
+    // cut the log back to before the removal, behind the store's back
+    let mut dump = Vec::new();
+    KvStore::dump(temp_dir.path(), &mut dump)?;
+    let dump = String::from_utf8(dump).unwrap();
+    let removal = dump
+        .lines()
+        .find_map(|line| line.strip_suffix(" RM key2"))
+        .expect("no removal in the log");
+    let offset: u64 = removal.split(':').nth(1).unwrap().parse().unwrap();
+    let log = temp_dir.path().join("1.log");
+    OpenOptions::new().write(true).open(&log)?.set_len(offset)?;
+
+    let report = store.verify()?;
+    assert!(!report.is_consistent());
+    assert_eq!((report.on_disk, report.indexed), (3, 2));
+    assert_eq!(report.missing_from_index, vec![b"key2".to_vec()]);
+    assert!(report.missing_from_log.is_empty());
+    assert_eq!(report.misplaced, vec![b"key3".to_vec()]);
+    drop(store);
+
+    // reopening rebuilds the index from what the log holds now
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.verify()?.is_consistent());
+    assert_eq!(store.keys_count_on_disk()?, 3);
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use kvs::{
+    KvStore, KvStoreConfig, KvsClient, KvsServer, Result, SharedQueueThreadPool, ThreadPool,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+use tempfile::TempDir;
+
+// Keeps every message logged by the crate, the logger is global to the process
+// so this file holds a single test
+struct Capture(Mutex<Vec<(Level, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = (record.level(), record.args().to_string());
+        self.0.lock().unwrap().push(message);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Capture = Capture(Mutex::new(Vec::new()));
+
+// Operations should be logged at debug, a compaction at info with its sizes.
+#[test]
+fn operations_are_logged() -> Result<()> {
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(LevelFilter::Debug);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().threshold(None);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.get("key1")?;
+    store.get("key2")?;
+    store.remove("key1")?;
+    let reclaimed = store.compact()?;
+
+    let messages = LOGGER.0.lock().unwrap();
+    let logged = |level: Level, prefix: &str| {
+        messages
+            .iter()
+            .any(|(l, message)| *l == level && message.starts_with(prefix))
+    };
+    assert!(logged(Level::Debug, "set key1 ("));
+    assert!(logged(Level::Debug, "get key1 hit"));
+    assert!(logged(Level::Debug, "get key2 miss"));
+    assert!(logged(Level::Debug, "removed key1"));
+    let compaction = messages
+        .iter()
+        .find(|(l, message)| *l == Level::Info && message.starts_with("compacted log"))
+        .map(|(_, message)| message.clone())
+        .expect("compaction wasn't logged");
+    assert!(compaction.ends_with(&format!("reclaimed {} bytes", reclaimed)));
+    // values are redacted by default
+    assert!(!messages
+        .iter()
+        .any(|(_, message)| message.contains("value")));
+    drop(messages);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::new().redact_values(false);
+    let mut store = KvStore::open_with(temp_dir.path(), config)?;
+    store.set("key3".to_owned(), "shown".to_owned())?;
+    store.get("key3")?;
+    let messages = LOGGER.0.lock().unwrap();
+    assert!(messages.contains(&(Level::Debug, "get key3 hit: shown".to_owned())));
+    assert!(messages
+        .iter()
+        .any(|(_, message)| message.starts_with("set key3 (") && message.ends_with("): shown")));
+    drop(messages);
+
+    // the server logs the kind and key of a request, never its value
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(1)?);
+    thread::spawn(move || server.serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key4".to_owned(), "secret".to_owned())?;
+    let messages = LOGGER.0.lock().unwrap();
+    assert!(messages
+        .iter()
+        .any(|(l, message)| *l == Level::Debug && message.starts_with("set key4 from ")));
+    assert!(!messages
+        .iter()
+        .any(|(_, message)| message.contains("secret")));
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use kvs::protocol::{self, VERSION};
+use kvs::{KvsError, Request, Response, Result};
+use std::io::{self, Cursor, Read};
+
+// Every request should read back as it was written.
+#[test]
+fn requests_round_trip() -> Result<()> {
+    let requests = vec![
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+        Request::Rm {
+            key: "key1".to_owned(),
+        },
+    ];
+    let mut buf = Vec::new();
+    for request in &requests {
+        protocol::write_message(&mut buf, request)?;
+    }
+
+    let mut reader = Cursor::new(buf);
+    for request in requests {
+        assert_eq!(
+            protocol::read_message::<Request>(&mut reader)?,
+            Some(request)
+        );
+    }
+    assert_eq!(protocol::read_message::<Request>(&mut reader)?, None);
+
+    Ok(())
+}
+
+// Every response should read back as it was written.
+#[test]
+fn responses_round_trip() -> Result<()> {
+    let responses = vec![
+        Response::Value(Some("value1".to_owned())),
+        Response::Value(None),
+        Response::Ok,
+        Response::Err("Key not found".to_owned()),
+    ];
+    let mut buf = Vec::new();
+    for response in &responses {
+        protocol::write_message(&mut buf, response)?;
+    }
+
+    let mut reader = Cursor::new(buf);
+    for response in responses {
+        assert_eq!(
+            protocol::read_message::<Response>(&mut reader)?,
+            Some(response)
+        );
+    }
+    assert_eq!(protocol::read_message::<Response>(&mut reader)?, None);
+
+    Ok(())
+}
+
+// A message of another version should be rejected, not parsed.
+#[test]
+fn version_mismatch_is_rejected() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_message(&mut buf, &Response::Ok)?;
+    assert_eq!(buf[0], VERSION);
+    buf[0] = VERSION + 1;
+
+    assert!(matches!(
+        protocol::read_message::<Response>(&mut Cursor::new(buf)),
+        Err(KvsError::ProtocolVersion(version)) if version == VERSION + 1
+    ));
+
+    Ok(())
+}
+
+// A message cut short should be an error rather than the end of the stream.
+#[test]
+fn truncated_message_is_rejected() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_message(&mut buf, &Response::Ok)?;
+    for len in 1..buf.len() {
+        let mut reader = Cursor::new(&buf[..len]);
+        assert!(protocol::read_message::<Response>(&mut reader).is_err());
+    }
+
+    Ok(())
+}
+
+// Reader interrupted before every read, as by a signal
+struct Interrupting<R> {
+    inner: R,
+    interrupted: bool,
+}
+
+impl<R: Read> Read for Interrupting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupted = !self.interrupted;
+        if self.interrupted {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        self.inner.read(buf)
+    }
+}
+
+// An interrupted read should be retried, not fail the message.
+#[test]
+fn interrupted_reads_are_retried() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_message(&mut buf, &Response::Ok)?;
+    let mut reader = Interrupting {
+        inner: Cursor::new(buf),
+        interrupted: false,
+    };
+
+    assert!(matches!(
+        protocol::read_message::<Response>(&mut reader)?,
+        Some(Response::Ok)
+    ));
+    assert!(protocol::read_message::<Response>(&mut reader)?.is_none());
+
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use kvs::protocol;
+use kvs::{
+    KvStore, KvsClient, KvsError, KvsServer, Request, Response, Result, SharedQueueThreadPool,
+    ThreadPool,
+};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Serve a fresh store on an ephemeral port, returning its address
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(store, pool).serve(listener));
+    Ok(addr)
+}
+
+// Requests over one connection should see each other's writes.
+#[test]
+fn client_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Errors should be reported to the client while the server keeps serving.
+#[test]
+fn server_reports_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvsError::ServerError(message)) => assert_eq!(message, "Key not found"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    // a later connection sees the earlier writes
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A client speaking another protocol version should get an error back.
+#[test]
+fn server_rejects_other_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut message = Vec::new();
+    protocol::write_message(
+        &mut message,
+        &Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    message[0] = protocol::VERSION + 1;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&message)?;
+
+    match protocol::read_message::<Response>(&mut stream)? {
+        Some(Response::Err(message)) => assert!(message.contains("protocol version")),
+        other => panic!("unexpected response {:?}", other),
+    }
+    // the server hangs up rather than guessing at the rest of the stream
+    assert_eq!(protocol::read_message::<Response>(&mut stream)?, None);
+
+    Ok(())
+}
+
+// Clients should be served side by side.
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    // an idle connection must not hold up the others
+    let _idle = KvsClient::connect(addr)?;
+    thread::scope(|scope| -> Result<()> {
+        let clients: Vec<_> = (0..8)
+            .map(|client_id| {
+                scope.spawn(move || -> Result<()> {
+                    let mut client = KvsClient::connect(addr)?;
+                    for key_id in 0..20 {
+                        let key = format!("key{}-{}", client_id, key_id);
+                        client.set(key.clone(), format!("value{}", key_id))?;
+                        assert_eq!(client.get(key)?, Some(format!("value{}", key_id)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().expect("client thread panicked")?;
+        }
+        Ok(())
+    })?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.get("key7-19".to_owned())?,
+        Some("value19".to_owned())
+    );
+
+    Ok(())
+}
+
+// A shut down server should end idle connections, flush and stop serving.
+#[test]
+fn shutdown_stops_serving() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(4)?);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    shutdown.shutdown();
+    serving.join().expect("server thread panicked")?;
+
+    // the idle connection was closed by the server
+    assert!(client.get("key1".to_owned()).is_err());
+    drop(client);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Requests sent back to back on one connection should be answered in order.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut requests = Vec::new();
+    for i in 0..10 {
+        requests.push(Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        });
+    }
+    for i in (0..11).rev() {
+        requests.push(Request::Get {
+            key: format!("key{}", i),
+        });
+    }
+    let mut message = Vec::new();
+    for request in &requests {
+        protocol::buffer_message(&mut message, request)?;
+    }
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&message)?;
+
+    let mut expected = vec![Response::Ok; 10];
+    expected.push(Response::Value(None));
+    expected.extend(
+        (0..10)
+            .rev()
+            .map(|i| Response::Value(Some(format!("value{}", i)))),
+    );
+    for response in &expected {
+        assert_eq!(
+            protocol::read_message::<Response>(&mut stream)?.as_ref(),
+            Some(response)
+        );
+    }
+
+    // the client pipelines the same way
+    let mut client = KvsClient::connect(addr)?;
+    requests.push(Request::Rm {
+        key: "missing".to_owned(),
+    });
+    expected.push(Response::Err("Key not found".to_owned()));
+    assert_eq!(client.pipeline(&requests)?, expected);
+
+    Ok(())
+}
+
+// A connection beyond the limit should wait until another one closes.
+#[test]
+fn connections_over_limit_wait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(4)?).max_connections(2);
+    thread::spawn(move || server.serve(listener));
+
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut waiting = TcpStream::connect(addr)?;
+    protocol::write_message(
+        &mut waiting,
+        &Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    waiting.set_read_timeout(Some(Duration::from_millis(300)))?;
+    match protocol::read_message::<Response>(&mut waiting) {
+        Err(KvsError::IoError(e)) => assert!(matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    drop(first);
+    waiting.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(
+        protocol::read_message::<Response>(&mut waiting)?,
+        Some(Response::Value(Some("value1".to_owned())))
+    );
+
+    Ok(())
+}
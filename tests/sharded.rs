@@ -0,0 +1,89 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result, ShardedKvStore};
+use std::collections::HashSet;
+use tempfile::TempDir;
+
+// Keys of the records in the log of a shard, from lines like `1:5 SET key value`
+fn logged_keys(dir: &std::path::Path) -> Result<HashSet<String>> {
+    let mut dump = Vec::new();
+    KvStore::dump(dir, &mut dump)?;
+    Ok(String::from_utf8(dump)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(' ').nth(2).unwrap().to_owned())
+        .collect())
+}
+
+// Every key should live in the shard its hash picks, and nowhere else.
+#[test]
+fn keys_are_placed_in_their_shard() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    for (n, shard) in store.shards().iter().enumerate() {
+        assert!(!shard.is_empty());
+        for key in shard.keys() {
+            assert_eq!(store.shard_of(&key), n);
+        }
+        for key in logged_keys(&temp_dir.path().join(format!("shard-{}", n)))? {
+            assert_eq!(store.shard_of(&key), n, "{} logged in shard {}", key, n);
+        }
+    }
+
+    // listing merges the shards
+    let mut keys = store.keys();
+    keys.sort();
+    let mut expected: Vec<_> = (1..100).map(|key_id| format!("key{}", key_id)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.iter().count(), 99);
+    assert_eq!(store.stats().live_keys, 99);
+    drop(store);
+
+    let mut store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    Ok(())
+}
+
+// Compaction should run shard by shard, keeping every value.
+#[test]
+fn shards_compact_on_their_own() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedKvStore::open(temp_dir.path(), 3)?;
+    for iter in 0..10 {
+        for key_id in 0..30 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    let before = store.stats();
+    assert!(store.compact()? > 0);
+    let after = store.stats();
+    assert!(after.log_size < before.log_size);
+    assert_eq!(after.compactions, 3);
+    assert_eq!(store.get("key7".to_owned())?, Some("value7-9".to_owned()));
+    Ok(())
+}
+
+// Reopening with another number of shards would misplace keys, so it is refused.
+#[test]
+fn shard_count_is_fixed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(ShardedKvStore::open(temp_dir.path(), 4)?);
+    assert!(matches!(
+        ShardedKvStore::open(temp_dir.path(), 2),
+        Err(KvsError::ShardCount(4))
+    ));
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use kvs::{KvsError, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+// Run jobs on the pool, returning once they have all finished
+fn run_jobs(pool: &impl ThreadPool, jobs: usize) -> usize {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..jobs {
+        let counter = counter.clone();
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..jobs {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("job did not finish");
+    }
+    counter.load(Ordering::SeqCst)
+}
+
+// Every job handed to the pool should run.
+#[test]
+fn shared_queue_runs_jobs() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    assert_eq!(run_jobs(&pool, 100), 100);
+    Ok(())
+}
+
+// Panicking jobs should not shrink the pool.
+#[test]
+fn shared_queue_survives_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    // more panics than threads, the pool would be empty without replacements
+    for _ in 0..8 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    assert_eq!(run_jobs(&pool, 100), 100);
+    Ok(())
+}
+
+// A pool without threads should be refused up front.
+#[test]
+fn shared_queue_needs_threads() {
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::NoThreads)
+    ));
+}